@@ -0,0 +1,2 @@
+cargo build --bin=pn-counter
+../maelstrom/maelstrom test -w pn-counter --bin ./target/debug/pn-counter --node-count 3 --rate 100 --time-limit 20 --nemesis partition
@@ -0,0 +1,2 @@
+cargo build --bin=lin-kv
+../maelstrom/maelstrom test -w lin-kv --bin ./target/debug/lin-kv --node-count 3 --concurrency 2n --rate 100 --time-limit 20 --nemesis partition
@@ -0,0 +1,247 @@
+use crate::{GossipScheduler, Message, Network, RangeSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Hashes of a set's values, bucketed by `value / width`. Empty buckets are
+/// left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Digest {
+    width: usize,
+    /// Bucket and hash pairs, sorted by bucket.
+    buckets: Vec<(usize, u64)>,
+}
+
+impl Digest {
+    pub fn of(set: &RangeSet, width: usize) -> Self {
+        let width = width.max(1);
+        let mut buckets = BTreeMap::new();
+        for (start, end) in set.ranges() {
+            for bucket in start / width..=end / width {
+                buckets.entry(bucket).or_insert_with(|| {
+                    let (first, last) = bucket_bounds(bucket, width);
+                    hash(&set.slice(first, last))
+                });
+            }
+        }
+        Self {
+            width,
+            buckets: buckets.into_iter().collect(),
+        }
+    }
+
+    /// Compares `set`, whose digest this is, with a peer's digest. Returns
+    /// the values of the buckets that differ, which the peer may lack, and
+    /// the differing buckets the peer has values in, which we may lack.
+    pub fn compare(&self, set: &RangeSet, theirs: &Digest) -> (RangeSet, Vec<usize>) {
+        let mut values = RangeSet::new();
+        for (bucket, hash) in &self.buckets {
+            if theirs.hash(*bucket) != Some(*hash) {
+                let (first, last) = bucket_bounds(*bucket, self.width);
+                values.extend(&set.slice(first, last));
+            }
+        }
+        let wanted = theirs
+            .buckets
+            .iter()
+            .filter(|(bucket, hash)| self.hash(*bucket) != Some(*hash))
+            .map(|(bucket, _)| *bucket)
+            .collect();
+        (values, wanted)
+    }
+
+    /// The values of `set` in the buckets whose hashes match `theirs`, which
+    /// the peer therefore has too.
+    pub fn matching(&self, set: &RangeSet, theirs: &Digest) -> RangeSet {
+        let mut values = RangeSet::new();
+        for (bucket, hash) in &self.buckets {
+            if theirs.hash(*bucket) == Some(*hash) {
+                let (first, last) = bucket_bounds(*bucket, self.width);
+                values.extend(&set.slice(first, last));
+            }
+        }
+        values
+    }
+
+    fn hash(&self, bucket: usize) -> Option<u64> {
+        self.buckets
+            .binary_search_by_key(&bucket, |(bucket, _)| *bucket)
+            .ok()
+            .map(|index| self.buckets[index].1)
+    }
+}
+
+fn bucket_bounds(bucket: usize, width: usize) -> (usize, usize) {
+    let first = bucket.saturating_mul(width);
+    (first, first.saturating_add(width - 1))
+}
+
+/// FNV-1a over the ranges, so every node hashes equal sets the same way.
+/// Cut to 53 bits, which JSON parsers that read numbers as doubles keep.
+fn hash(set: &RangeSet) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (start, end) in set.ranges() {
+        for byte in start.to_le_bytes().into_iter().chain(end.to_le_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash >> 11
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPayload {
+    /// Starts a round with the digest of the initiator's set.
+    SyncDigest { digest: Digest },
+    /// The responder's values in the buckets that differ, the buckets it
+    /// wants from the initiator, and its digest.
+    SyncDiff {
+        values: RangeSet,
+        wanted: Vec<usize>,
+        digest: Digest,
+    },
+    /// The initiator's values in the buckets the responder wanted.
+    SyncPush { values: RangeSet },
+}
+
+/// What a sync message taught us.
+#[derive(Debug, Default)]
+pub struct Synced {
+    /// How many values were new to us.
+    pub new: usize,
+    /// Values the peer is now known to have.
+    pub peer_has: RangeSet,
+}
+
+/// Push-pull anti-entropy for a set of integers.
+///
+/// Every round, the node sends the digest of its set to the next peer. The
+/// peer answers with its values in the buckets whose hashes differ and asks
+/// for the initiator's values in them, so any difference is fixed within
+/// one and a half round trips, whatever gossip acks were lost. Nodes route
+/// `sync_digest`, `sync_diff` and `sync_push` messages to `receive`.
+#[derive(Clone, Debug)]
+pub struct AntiEntropy {
+    set: Arc<Mutex<RangeSet>>,
+    network: Network,
+    scheduler: GossipScheduler,
+    bucket_width: usize,
+    next_peer: Arc<AtomicUsize>,
+}
+
+impl AntiEntropy {
+    /// Messages sent are counted on `scheduler`.
+    pub fn new(
+        set: Arc<Mutex<RangeSet>>,
+        network: Network,
+        scheduler: GossipScheduler,
+        bucket_width: usize,
+    ) -> Self {
+        Self {
+            set,
+            network,
+            scheduler,
+            bucket_width,
+            next_peer: Default::default(),
+        }
+    }
+
+    /// Starts a round every `interval`, with the peers returned by `peers`
+    /// in turn.
+    pub fn start<TPeers>(&self, interval: Duration, peers: TPeers)
+    where
+        TPeers: Fn() -> Vec<String> + Send + 'static,
+    {
+        let this = self.clone();
+        self.network
+            .timers()
+            .every(interval, interval / 10, move || {
+                let this = this.clone();
+                let peers = peers();
+                async move {
+                    if peers.is_empty() {
+                        return;
+                    }
+                    let peer = &peers[this.next_peer.fetch_add(1, Ordering::Relaxed) % peers.len()];
+                    let digest = this.digest();
+                    this.send(peer, SyncPayload::SyncDigest { digest }).await;
+                }
+            });
+    }
+
+    pub async fn receive(&self, msg: Message<SyncPayload>) -> Synced {
+        match msg.body.payload {
+            SyncPayload::SyncDigest { digest: theirs } => {
+                let ours = self.digest();
+                let (values, wanted) = {
+                    let set = self.set.lock().expect("Unable to lock set");
+                    ours.compare(&set, &theirs)
+                };
+                if !values.is_empty() || !wanted.is_empty() {
+                    let diff = SyncPayload::SyncDiff {
+                        values,
+                        wanted,
+                        digest: ours,
+                    };
+                    self.send(&msg.src, diff).await;
+                }
+                Synced::default()
+            }
+            SyncPayload::SyncDiff {
+                values,
+                wanted,
+                digest: theirs,
+            } => {
+                let (new, mut peer_has, push) = {
+                    let mut set = self.set.lock().expect("Unable to lock set");
+                    let ours = Digest::of(&set, self.bucket_width);
+                    let peer_has = ours.matching(&set, &theirs);
+                    let mut push = RangeSet::new();
+                    for bucket in wanted {
+                        let (first, last) = bucket_bounds(bucket, self.bucket_width);
+                        push.extend(&set.slice(first, last));
+                    }
+                    (set.extend(&values), peer_has, push)
+                };
+                peer_has.extend(&values);
+                if !push.is_empty() {
+                    self.send(&msg.src, SyncPayload::SyncPush { values: push })
+                        .await;
+                }
+                Synced { new, peer_has }
+            }
+            SyncPayload::SyncPush { values } => {
+                let new = self.set.lock().expect("Unable to lock set").extend(&values);
+                Synced {
+                    new,
+                    peer_has: values,
+                }
+            }
+        }
+    }
+
+    fn digest(&self) -> Digest {
+        Digest::of(
+            &self.set.lock().expect("Unable to lock set"),
+            self.bucket_width,
+        )
+    }
+
+    async fn send(&self, peer: &str, payload: SyncPayload) {
+        let msg = Message::new(
+            self.network.node_id().to_string(),
+            peer.to_string(),
+            payload,
+        );
+        self.network.send(&msg).await;
+        self.scheduler.record_sent(1);
+    }
+}
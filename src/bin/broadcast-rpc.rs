@@ -1,205 +1,550 @@
-use core::panic;
-use gossip::{Message, Network, Node, Runtime};
-use rand::Rng;
+use gossip::{
+    AntiEntropy, GossipConfig, GossipScheduler, Message, Network, Node, RangeSet, Request, Router,
+    Runtime, SyncPayload, Topology, TopologyKind,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
     collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
     time::Duration,
 };
+use tokio::sync::broadcast::error::RecvError;
+
+const GOSSIP_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long an announced value may take to arrive through the tree before
+/// it is grafted from the announcer.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(400);
+/// Values per anti-entropy bucket.
+const ANTI_ENTROPY_BUCKET_WIDTH: usize = 64;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, BoradcastNode>::run().await
+    Runtime::<Value, BoradcastNode>::run().await
 }
 
 #[derive(Clone)]
-struct BoradcastNode {
-    messages: Arc<Mutex<HashSet<usize>>>,
+pub struct BoradcastNode {
+    messages: Arc<Mutex<RangeSet>>,
+    known_messages: Arc<Mutex<HashMap<String, RangeSet>>>,
+    /// The messages of the pushes to each peer that are not acked yet.
+    in_flight: Arc<Mutex<HashMap<String, RangeSet>>>,
     network: Network,
+    topology: Topology,
+    scheduler: GossipScheduler,
+    anti_entropy: AntiEntropy,
+    plumtree: Option<Plumtree>,
+    router: Arc<Router<BoradcastNode>>,
 }
 
-impl Node<Payload> for BoradcastNode {
+impl Node<Value> for BoradcastNode {
     fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
-        let messages: Arc<Mutex<HashSet<usize>>> = Default::default();
-        let known_messages: Arc<Mutex<HashMap<String, HashSet<usize>>>> = Default::default();
+        let messages: Arc<Mutex<RangeSet>> = Default::default();
+        let known_messages: Arc<Mutex<HashMap<String, RangeSet>>> = Default::default();
+        let in_flight: Arc<Mutex<HashMap<String, RangeSet>>> = Default::default();
+        let topology = Topology::new(id, neighbors, TopologyKind::from_env());
+        BoradcastNode::follow_peer_status(&network, topology.clone());
 
-        BoradcastNode::gossip(
-            id,
-            neighbors,
+        let config = GossipConfig::from_env();
+        let plumtree = Plumtree::from_env();
+        let scheduler = BoradcastNode::gossip(
+            config.clone(),
+            topology.clone(),
+            plumtree.clone(),
             messages.clone(),
             known_messages.clone(),
+            in_flight.clone(),
+            network.clone(),
+        )
+        .with_heartbeats(&network);
+        let anti_entropy = AntiEntropy::new(
+            messages.clone(),
             network.clone(),
+            scheduler.clone(),
+            ANTI_ENTROPY_BUCKET_WIDTH,
         );
+        let peers = topology.clone();
+        anti_entropy.start(config.anti_entropy_interval, move || peers.peers());
 
-        Self { messages, network }
-    }
+        let router = Router::new()
+            .route("broadcast", Self::handle_broadcast)
+            .route("read", Self::handle_read)
+            .route("gossip", Self::handle_gossip)
+            .route("ihave", Self::handle_ihave)
+            .route("graft", Self::handle_graft)
+            .route("prune", Self::handle_prune)
+            .route("topology", Self::handle_topology)
+            .route("sync_digest", Self::handle_sync)
+            .route("sync_diff", Self::handle_sync)
+            .route("sync_push", Self::handle_sync);
 
-    async fn handle_message(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        match msg.get_payload() {
-            Payload::Broadcast { .. } => self.handle_broadcast(msg).await?,
-            Payload::Read => self.handle_read(msg).await?,
-            Payload::Gossip { .. } => self.handle_gossip(msg).await?,
-            Payload::Topology { .. } => self.handle_topology(msg).await?,
-            Payload::ReadOk { .. }
-            | Payload::BroadcastOk
-            | Payload::TopologyOk
-            | Payload::GossipOk { .. } => {
-                panic!("got invalid message")
-            }
+        Self {
+            messages,
+            known_messages,
+            in_flight,
+            network,
+            topology,
+            scheduler,
+            anti_entropy,
+            plumtree,
+            router: Arc::new(router),
         }
+    }
+
+    async fn handle_message(&self, msg: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), msg).await
+    }
+
+    async fn on_shutdown(&self) -> anyhow::Result<()> {
+        tracing::info!(
+            ops = self.scheduler.ops(),
+            messages = self.scheduler.messages(),
+            msgs_per_op = self.scheduler.msgs_per_op(),
+            "gossip stats"
+        );
         Ok(())
     }
 }
 
 impl BoradcastNode {
     fn gossip(
-        node_id: String,
-        all_nodes: Vec<String>,
-        messages: Arc<Mutex<HashSet<usize>>>,
-        known_messages: Arc<Mutex<HashMap<String, HashSet<usize>>>>,
+        config: GossipConfig,
+        topology: Topology,
+        plumtree: Option<Plumtree>,
+        messages: Arc<Mutex<RangeSet>>,
+        known_messages: Arc<Mutex<HashMap<String, RangeSet>>>,
+        in_flight: Arc<Mutex<HashMap<String, RangeSet>>>,
         network: Network,
-    ) {
-        tokio::spawn(async move {
-            loop {
-                let rng = rand::rng().random_range(0..100);
-                tokio::time::sleep(Duration::from_millis(10 + rng)).await;
-                for dest_id in all_nodes
-                    .clone()
-                    .into_iter()
-                    .filter(|node| node_id != **node)
-                {
-                    let empty_set = HashSet::new();
-
-                    let messages = {
-                        let messages_known_to_node =
-                            known_messages.lock().expect("Can't lock known messages");
-                        let messages_known_to_node =
-                            messages_known_to_node.get(&dest_id).unwrap_or(&empty_set);
-                        let messages: HashSet<usize> = {
-                            messages
-                                .lock()
-                                .expect("Can't lock messages")
-                                .difference(messages_known_to_node)
-                                .copied()
-                                .collect()
-                        };
-                        messages
-                    };
-
+    ) -> GossipScheduler {
+        let timers = network.timers().clone();
+        GossipScheduler::start(&timers, config, move || {
+            let (eager, lazy) = match &plumtree {
+                Some(plumtree) => plumtree.split(topology.peers()),
+                None => (topology.peers(), Vec::new()),
+            };
+            let topology = topology.clone();
+            let plumtree = plumtree.clone();
+            let messages = messages.clone();
+            let known_messages = known_messages.clone();
+            let in_flight = in_flight.clone();
+            let network = network.clone();
+            async move {
+                let mut sent = 0;
+                for dest_id in eager {
+                    let messages = unknown_to(&messages, &known_messages, &in_flight, &dest_id);
                     if messages.is_empty() {
                         continue;
                     }
+                    push(
+                        &network,
+                        &topology,
+                        &known_messages,
+                        &in_flight,
+                        dest_id,
+                        messages,
+                    );
+                    sent += 1;
+                }
 
-                    let mut msg = Message::new(
-                        node_id.clone(),
-                        dest_id.clone(),
-                        Payload::Gossip { messages },
+                let Some(plumtree) = plumtree else {
+                    return sent;
+                };
+                for dest_id in lazy {
+                    let messages = plumtree.announce(
+                        &dest_id,
+                        unknown_to(&messages, &known_messages, &in_flight, &dest_id),
                     );
-                    let n = network.clone();
-                    let b = known_messages.clone();
-                    tokio::spawn(async move {
-                        match n.rpc(&mut msg).await {
-                            Ok(reply) => {
-                                eprintln!("Got reply for message {reply:?}");
-
-                                let Payload::GossipOk { messages } = reply.body.payload else {
-                                    panic!("expected gossip_ok");
-                                };
-                                let mut known_message = b.lock().expect("Unable to get lock");
-
-                                known_message
-                                    .entry(dest_id.clone())
-                                    .and_modify(|v| v.extend(messages.clone()))
-                                    .or_insert(messages.clone());
-                            }
-                            Err(_) => {
-                                // continue
-                            }
-                        }
-                    });
+                    if messages.is_empty() {
+                        continue;
+                    }
+                    let msg =
+                        Message::new(network.node_id().to_string(), dest_id, IHave { messages });
+                    network.send(&msg).await;
+                    sent += 1;
+                }
+                sent
+            }
+        })
+    }
+
+    /// Routes around the peers the failure detector suspects, if it is on.
+    fn follow_peer_status(network: &Network, topology: Topology) {
+        let mut events = network.peer_events();
+        let task = tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => topology.record_status(&event.peer, event.status),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
                 }
             }
         });
+        network.timers().track(task.abort_handle());
     }
 
-    async fn handle_broadcast(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Broadcast { message } = msg.body.payload else {
-            panic!("expected broadcast");
-        };
+    async fn handle_broadcast(self, msg: Message<Broadcast>) -> anyhow::Result<()> {
+        let Broadcast { message } = msg.body.payload;
 
+        self.scheduler.record_op();
+        if self
+            .messages
+            .lock()
+            .expect("Unable to get lock")
+            .insert(message)
         {
-            let mut messages = self.messages.lock().expect("Unable to get lock");
-            messages.insert(message);
+            self.scheduler.record_new(1);
         }
-        let reply = msg.reply(Payload::BroadcastOk);
+        let reply = msg.reply(BroadcastOk {});
         self.network.send(&reply).await;
         Ok(())
     }
 
-    async fn handle_read(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Read = msg.body.payload else {
-            panic!("expected read");
-        };
+    async fn handle_read(self, msg: Message<Read>) -> anyhow::Result<()> {
+        self.scheduler.record_op();
 
-        let reply = msg.reply(Payload::ReadOk {
-            messages: self.messages.lock().expect("Unable to get lock").clone(),
+        let reply = msg.reply(ReadOk {
+            messages: self
+                .messages
+                .lock()
+                .expect("Unable to get lock")
+                .iter()
+                .collect(),
         });
         self.network.send(&reply).await;
         Ok(())
     }
 
-    async fn handle_gossip(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Gossip {
-            messages: incoming_messages,
-        } = &msg.body.payload
-        else {
-            panic!("expected gossip");
-        };
+    async fn handle_gossip(self, msg: Message<Gossip>) -> anyhow::Result<()> {
+        let incoming_messages = &msg.body.payload.messages;
 
-        {
+        let new_messages = {
             let mut messages = self.messages.lock().expect("Unable to get lock");
-            messages.extend(incoming_messages);
+            let new_messages = incoming_messages.difference(&messages);
+            messages.extend(&new_messages);
+            new_messages
+        };
+        self.scheduler.record_new(new_messages.len());
+        if let Some(plumtree) = &self.plumtree {
+            plumtree.delivered(&msg.src, &new_messages);
+            // A push with nothing new, that repeats messages another peer
+            // delivered first, means the sender's link to us is redundant
+            // with the tree, so demote it to announcements. A retry of the
+            // sender's own push is no such sign.
+            if new_messages.is_empty() && plumtree.is_redundant(&msg.src, incoming_messages) {
+                plumtree.prune(&msg.src);
+                let prune = Message::new(msg.dest.clone(), msg.src.clone(), Prune {});
+                self.network.send(&prune).await;
+                self.scheduler.record_sent(1);
+            } else if !new_messages.is_empty() {
+                plumtree.graft(&msg.src);
+            }
         }
+        // The sender already has these, so never gossip them back to it.
+        self.known_messages
+            .lock()
+            .expect("Unable to get lock")
+            .entry(msg.src.clone())
+            .or_default()
+            .extend(incoming_messages);
 
-        let reply = msg.reply(Payload::GossipOk {
-            messages: incoming_messages.clone(),
-        });
+        let reply = msg.reply(GossipOk {});
         self.network.send(&reply).await;
+        self.scheduler.record_sent(1);
+        Ok(())
+    }
+
+    async fn handle_ihave(self, msg: Message<IHave>) -> anyhow::Result<()> {
+        let announced = msg.body.payload.messages;
+        let Some(plumtree) = self.plumtree.clone() else {
+            return Ok(());
+        };
+
+        self.known_messages
+            .lock()
+            .expect("Unable to get lock")
+            .entry(msg.src.clone())
+            .or_default()
+            .extend(&announced);
+
+        let missing = announced.difference(&self.messages.lock().expect("Unable to get lock"));
+        if missing.is_empty() {
+            return Ok(());
+        }
+        // Give the tree a chance to deliver them first.
+        let timers = self.network.timers().clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(GRAFT_TIMEOUT).await;
+            let missing = missing.difference(&self.messages.lock().expect("Unable to get lock"));
+            if missing.is_empty() {
+                return;
+            }
+            plumtree.graft(&msg.src);
+            let graft = Message::new(
+                msg.dest.clone(),
+                msg.src.clone(),
+                Graft { messages: missing },
+            );
+            self.network.send(&graft).await;
+            self.scheduler.record_sent(1);
+        });
+        timers.track(task.abort_handle());
         Ok(())
     }
 
-    async fn handle_topology(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Topology { .. } = &msg.body.payload else {
-            panic!("expected topology");
+    async fn handle_graft(self, msg: Message<Graft>) -> anyhow::Result<()> {
+        let requested = &msg.body.payload.messages;
+        let Some(plumtree) = &self.plumtree else {
+            return Ok(());
         };
 
-        let reply = msg.reply(Payload::TopologyOk);
+        plumtree.graft(&msg.src);
+        let messages = requested.intersection(&self.messages.lock().expect("Unable to get lock"));
+        if !messages.is_empty() {
+            push(
+                &self.network,
+                &self.topology,
+                &self.known_messages,
+                &self.in_flight,
+                msg.src.clone(),
+                messages,
+            );
+            self.scheduler.record_sent(1);
+        }
+        Ok(())
+    }
+
+    async fn handle_prune(self, msg: Message<Prune>) -> anyhow::Result<()> {
+        if let Some(plumtree) = &self.plumtree {
+            plumtree.prune(&msg.src);
+        }
+        Ok(())
+    }
+
+    async fn handle_sync(self, msg: Message<SyncPayload>) -> anyhow::Result<()> {
+        let src = msg.src.clone();
+        let synced = self.anti_entropy.receive(msg).await;
+        self.scheduler.record_new(synced.new);
+        self.known_messages
+            .lock()
+            .expect("Unable to get lock")
+            .entry(src)
+            .or_default()
+            .extend(&synced.peer_has);
+        Ok(())
+    }
+
+    async fn handle_topology(self, msg: Message<UpdateTopology>) -> anyhow::Result<()> {
+        self.topology.set_provided(&msg.body.payload.topology);
+
+        let reply = msg.reply(TopologyOk {});
         self.network.send(&reply).await;
         Ok(())
     }
 }
 
+/// The messages `dest_id` is not known to have and that are not on their
+/// way to it already.
+fn unknown_to(
+    messages: &Mutex<RangeSet>,
+    known_messages: &Mutex<HashMap<String, RangeSet>>,
+    in_flight: &Mutex<HashMap<String, RangeSet>>,
+    dest_id: &str,
+) -> RangeSet {
+    let empty_set = RangeSet::new();
+    let messages_known_to_node = known_messages.lock().expect("Can't lock known messages");
+    let messages_known_to_node = messages_known_to_node.get(dest_id).unwrap_or(&empty_set);
+    let in_flight = in_flight.lock().expect("Can't lock in flight messages");
+    let in_flight = in_flight.get(dest_id).unwrap_or(&empty_set);
+    messages
+        .lock()
+        .expect("Can't lock messages")
+        .difference(messages_known_to_node)
+        .difference(in_flight)
+}
+
+/// Gossips `messages` to `dest_id` in the background. They count as in
+/// flight until the push is acked or times out.
+fn push(
+    network: &Network,
+    topology: &Topology,
+    known_messages: &Arc<Mutex<HashMap<String, RangeSet>>>,
+    in_flight: &Arc<Mutex<HashMap<String, RangeSet>>>,
+    dest_id: String,
+    messages: RangeSet,
+) {
+    in_flight
+        .lock()
+        .expect("Unable to get lock")
+        .entry(dest_id.clone())
+        .or_default()
+        .extend(&messages);
+    let timers = network.timers().clone();
+    let network = network.clone();
+    let known_messages = known_messages.clone();
+    let in_flight = in_flight.clone();
+    let topology = topology.clone();
+    let task = tokio::spawn(async move {
+        let gossip = Gossip {
+            messages: messages.clone(),
+        };
+        let result = network
+            .call_with_timeout(&dest_id, gossip, GOSSIP_TIMEOUT)
+            .await;
+        if let Some(pending) = in_flight
+            .lock()
+            .expect("Unable to get lock")
+            .get_mut(&dest_id)
+        {
+            *pending = pending.difference(&messages);
+        }
+        match result {
+            // The reply only acks the call; we know what we sent.
+            Ok(GossipOk {}) => {
+                topology.record_ack(&dest_id);
+                let mut known_message = known_messages.lock().expect("Unable to get lock");
+                known_message
+                    .entry(dest_id.clone())
+                    .or_default()
+                    .extend(&messages);
+            }
+            Err(_) => topology.record_missed(&dest_id),
+        }
+    });
+    timers.track(task.abort_handle());
+}
+
+/// Plumtree's split of the peers. New messages are pushed to the eager peers
+/// and only announced to the lazy ones. Every peer starts out eager; the
+/// links that deliver duplicates get pruned to lazy until the eager ones form
+/// a spanning tree, and a lazy link that announces messages the tree lost is
+/// grafted back.
+#[derive(Clone, Debug, Default)]
+struct Plumtree {
+    lazy: Arc<Mutex<HashSet<String>>>,
+    /// What was announced to each lazy peer, so it is announced only once.
+    announced: Arc<Mutex<HashMap<String, RangeSet>>>,
+    /// The messages each peer was the first to push to us.
+    delivered_by: Arc<Mutex<HashMap<String, RangeSet>>>,
+}
+
+impl Plumtree {
+    /// Reads `GOSSIP_MODE`: `plumtree` enables it, anything else pushes to
+    /// every peer.
+    fn from_env() -> Option<Self> {
+        (std::env::var("GOSSIP_MODE").as_deref() == Ok("plumtree")).then(Self::default)
+    }
+
+    /// Splits `peers` into the eager and the lazy ones.
+    fn split(&self, peers: Vec<String>) -> (Vec<String>, Vec<String>) {
+        let lazy = self.lazy.lock().expect("Unable to lock lazy peers");
+        peers.into_iter().partition(|peer| !lazy.contains(peer))
+    }
+
+    fn graft(&self, peer: &str) {
+        self.lazy
+            .lock()
+            .expect("Unable to lock lazy peers")
+            .remove(peer);
+    }
+
+    fn prune(&self, peer: &str) {
+        self.lazy
+            .lock()
+            .expect("Unable to lock lazy peers")
+            .insert(peer.to_string());
+    }
+
+    /// Records that `peer` was the first to push `messages` to us.
+    fn delivered(&self, peer: &str, messages: &RangeSet) {
+        if messages.is_empty() {
+            return;
+        }
+        self.delivered_by
+            .lock()
+            .expect("Unable to lock delivered messages")
+            .entry(peer.to_string())
+            .or_default()
+            .extend(messages);
+    }
+
+    /// Whether a push from `peer` repeats messages some other way delivered
+    /// first, rather than only ones `peer` itself did.
+    fn is_redundant(&self, peer: &str, messages: &RangeSet) -> bool {
+        let delivered_by = self
+            .delivered_by
+            .lock()
+            .expect("Unable to lock delivered messages");
+        match delivered_by.get(peer) {
+            Some(delivered) => !messages.difference(delivered).is_empty(),
+            None => !messages.is_empty(),
+        }
+    }
+
+    /// The ones of `messages` not yet announced to `peer`, which are now.
+    fn announce(&self, peer: &str, messages: RangeSet) -> RangeSet {
+        let mut announced = self.announced.lock().expect("Unable to lock announced");
+        let announced = announced.entry(peer.to_string()).or_default();
+        let messages = messages.difference(announced);
+        announced.extend(&messages);
+        messages
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Broadcast {
-        message: usize,
-    },
-    BroadcastOk,
-    Read,
-    ReadOk {
-        messages: HashSet<usize>,
-    },
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
-    TopologyOk,
-    Gossip {
-        messages: HashSet<usize>,
-    },
-    GossipOk {
-        messages: HashSet<usize>,
-    },
+#[serde(tag = "type", rename = "broadcast")]
+struct Broadcast {
+    message: usize,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "broadcast_ok")]
+struct BroadcastOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read")]
+struct Read {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk {
+    messages: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "topology")]
+struct UpdateTopology {
+    topology: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "topology_ok")]
+struct TopologyOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "gossip")]
+struct Gossip {
+    messages: RangeSet,
+}
+impl Request for Gossip {
+    type Response = GossipOk;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "gossip_ok")]
+struct GossipOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "ihave")]
+struct IHave {
+    messages: RangeSet,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "graft")]
+struct Graft {
+    messages: RangeSet,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "prune")]
+struct Prune {}
@@ -1,194 +1,310 @@
-use core::panic;
-use gossip::{Message, Network, Node, Runtime};
-use rand::Rng;
+use gossip::{
+    AntiEntropy, GossipConfig, GossipScheduler, Message, Network, Node, RangeSet, Router, Runtime,
+    SyncPayload, Topology, TopologyKind,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     sync::{Arc, Mutex},
-    time::Duration,
 };
 
+/// Values per anti-entropy bucket.
+const ANTI_ENTROPY_BUCKET_WIDTH: usize = 64;
+/// Unacked rounds remembered per peer. Older ones are forgotten; every round
+/// resends whatever the earlier ones did that is still not known acked.
+const MAX_UNACKED_ROUNDS: usize = 64;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, BoradcastNode>::run().await
+    Runtime::<Value, BoradcastNode>::run().await
 }
 
 #[derive(Clone)]
-struct BoradcastNode {
-    messages: Arc<Mutex<HashSet<usize>>>,
-    known_messages: Arc<Mutex<HashMap<String, HashSet<usize>>>>,
+pub struct BoradcastNode {
+    messages: Arc<Mutex<RangeSet>>,
+    known_messages: Arc<Mutex<HashMap<String, RangeSet>>>,
+    last_gossip: Arc<Mutex<HashMap<String, SentGossip>>>,
     network: Network,
+    topology: Topology,
+    scheduler: GossipScheduler,
+    anti_entropy: AntiEntropy,
+    router: Arc<Router<BoradcastNode>>,
 }
 
-impl Node<Payload> for BoradcastNode {
+impl Node<Value> for BoradcastNode {
     fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
-        let messages: Arc<Mutex<HashSet<usize>>> = Default::default();
-        let known_messages: Arc<Mutex<HashMap<String, HashSet<usize>>>> = Default::default();
+        let messages: Arc<Mutex<RangeSet>> = Default::default();
+        let known_messages: Arc<Mutex<HashMap<String, RangeSet>>> = Default::default();
+        let last_gossip: Arc<Mutex<HashMap<String, SentGossip>>> = Default::default();
+        let topology = Topology::new(id.clone(), neighbors, TopologyKind::from_env());
 
-        BoradcastNode::gossip(
+        let config = GossipConfig::from_env();
+        let scheduler = BoradcastNode::gossip(
+            config.clone(),
             id,
-            neighbors,
+            topology.clone(),
             messages.clone(),
             known_messages.clone(),
+            last_gossip.clone(),
+            network.clone(),
+        )
+        .with_heartbeats(&network);
+        let anti_entropy = AntiEntropy::new(
+            messages.clone(),
             network.clone(),
+            scheduler.clone(),
+            ANTI_ENTROPY_BUCKET_WIDTH,
         );
+        let peers = topology.clone();
+        anti_entropy.start(config.anti_entropy_interval, move || peers.peers());
+
+        let router = Router::new()
+            .route("broadcast", Self::handle_broadcast)
+            .route("read", Self::handle_read)
+            .route("gossip", Self::handle_gossip)
+            .route("gossip_ok", Self::handle_gossip_ok)
+            .route("topology", Self::handle_topology)
+            .route("sync_digest", Self::handle_sync)
+            .route("sync_diff", Self::handle_sync)
+            .route("sync_push", Self::handle_sync);
 
         Self {
             messages,
             known_messages,
+            last_gossip,
             network,
+            topology,
+            scheduler,
+            anti_entropy,
+            router: Arc::new(router),
         }
     }
 
-    async fn handle_message(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        match msg.get_payload() {
-            Payload::Broadcast { .. } => self.handle_broadcast(msg).await?,
-            Payload::Read => self.handle_read(msg).await?,
-            Payload::Gossip { .. } => self.handle_gossip(msg).await?,
-            Payload::GossipOk { .. } => self.handle_gossip_ok(msg)?,
-            Payload::Topology { .. } => self.handle_topology(msg).await?,
-            Payload::ReadOk { .. } | Payload::BroadcastOk | Payload::TopologyOk => {
-                panic!("got invalid message")
-            }
-        }
+    async fn handle_message(&self, msg: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), msg).await
+    }
+
+    async fn on_shutdown(&self) -> anyhow::Result<()> {
+        tracing::info!(
+            ops = self.scheduler.ops(),
+            messages = self.scheduler.messages(),
+            msgs_per_op = self.scheduler.msgs_per_op(),
+            "gossip stats"
+        );
         Ok(())
     }
 }
 
 impl BoradcastNode {
     fn gossip(
+        config: GossipConfig,
         node_id: String,
-        all_nodes: Vec<String>,
-        messages: Arc<Mutex<HashSet<usize>>>,
-        known_messages: Arc<Mutex<HashMap<String, HashSet<usize>>>>,
+        topology: Topology,
+        messages: Arc<Mutex<RangeSet>>,
+        known_messages: Arc<Mutex<HashMap<String, RangeSet>>>,
+        last_gossip: Arc<Mutex<HashMap<String, SentGossip>>>,
         network: Network,
-    ) {
-        tokio::spawn(async move {
-            loop {
-                let rng = rand::rng().random_range(0..100);
-                tokio::time::sleep(Duration::from_millis(1000 + rng)).await;
-                for dest_id in all_nodes.iter().filter(|node| node_id != **node) {
-                    let empty_set = HashSet::new();
+    ) -> GossipScheduler {
+        let timers = network.timers().clone();
+        GossipScheduler::start(&timers, config, move || {
+            let node_id = node_id.clone();
+            let peers = topology.peers();
+            let topology = topology.clone();
+            let messages = messages.clone();
+            let known_messages = known_messages.clone();
+            let last_gossip = last_gossip.clone();
+            let network = network.clone();
+            async move {
+                let mut sent = 0;
+                for dest_id in &peers {
+                    let empty_set = RangeSet::new();
 
                     let messages = {
                         let messages_known_to_node =
                             known_messages.lock().expect("Can't lock known messages");
                         let messages_known_to_node =
                             messages_known_to_node.get(dest_id).unwrap_or(&empty_set);
-                        let messages: HashSet<usize> = messages
+                        messages
                             .lock()
                             .expect("Can't lock messages")
                             .difference(messages_known_to_node)
-                            .copied()
-                            .collect();
-                        messages
                     };
                     if messages.is_empty() {
                         continue;
                     }
 
-                    let msg = Message::new(
-                        node_id.clone(),
-                        dest_id.clone(),
-                        Payload::Gossip {
-                            messages: messages.clone(),
-                        },
-                    );
+                    // Rounds stay outstanding until acked, so acks that
+                    // arrive after later rounds were sent still count.
+                    let round = {
+                        let mut last_gossip = last_gossip.lock().expect("Can't lock gossip");
+                        let sent = last_gossip.entry(dest_id.clone()).or_default();
+                        sent.round += 1;
+                        sent.unacked.insert(sent.round, messages.clone());
+                        if sent.unacked.len() > MAX_UNACKED_ROUNDS {
+                            sent.unacked.pop_first();
+                        }
+                        sent.round
+                    };
+                    let msg =
+                        Message::new(node_id.clone(), dest_id.clone(), Gossip { round, messages });
+                    topology.record_sent(dest_id);
                     network.send(&msg).await;
+                    sent += 1;
                 }
+                sent
             }
-        });
+        })
     }
 
-    async fn handle_broadcast(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Broadcast { message } = msg.body.payload else {
-            panic!("expected broadcast");
-        };
+    async fn handle_broadcast(self, msg: Message<Broadcast>) -> anyhow::Result<()> {
+        let Broadcast { message } = msg.body.payload;
 
+        self.scheduler.record_op();
+        if self
+            .messages
+            .lock()
+            .expect("Unable to get lock")
+            .insert(message)
         {
-            let mut messages = self.messages.lock().expect("Unable to get lock");
-            messages.insert(message);
+            self.scheduler.record_new(1);
         }
-        let reply = msg.reply(Payload::BroadcastOk);
+        let reply = msg.reply(BroadcastOk {});
         self.network.send(&reply).await;
         Ok(())
     }
 
-    async fn handle_read(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Read = msg.body.payload else {
-            panic!("expected read");
-        };
+    async fn handle_read(self, msg: Message<Read>) -> anyhow::Result<()> {
+        self.scheduler.record_op();
 
-        let reply = msg.reply(Payload::ReadOk {
-            messages: self.messages.lock().expect("Unable to get lock").clone(),
+        let reply = msg.reply(ReadOk {
+            messages: self
+                .messages
+                .lock()
+                .expect("Unable to get lock")
+                .iter()
+                .collect(),
         });
         self.network.send(&reply).await;
         Ok(())
     }
 
-    async fn handle_gossip(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Gossip {
+    async fn handle_gossip(self, msg: Message<Gossip>) -> anyhow::Result<()> {
+        let Gossip {
+            round,
             messages: incoming_messages,
-        } = &msg.body.payload
-        else {
-            panic!("expected gossip");
-        };
+        } = &msg.body.payload;
 
-        {
-            let mut messages = self.messages.lock().expect("Unable to get lock");
-            messages.extend(incoming_messages);
-        }
+        let new_messages = self
+            .messages
+            .lock()
+            .expect("Unable to get lock")
+            .extend(incoming_messages);
+        self.scheduler.record_new(new_messages);
+        // The sender already has these, so never gossip them back to it.
+        self.known_messages
+            .lock()
+            .expect("Unable to get lock")
+            .entry(msg.src.clone())
+            .or_default()
+            .extend(incoming_messages);
 
-        let reply = msg.reply(Payload::GossipOk {
-            messages: incoming_messages.clone(),
-        });
+        let reply = msg.reply(GossipOk { round: *round });
         self.network.send(&reply).await;
+        self.scheduler.record_sent(1);
         Ok(())
     }
 
-    async fn handle_topology(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Topology { .. } = &msg.body.payload else {
-            panic!("expected topology");
-        };
+    async fn handle_sync(self, msg: Message<SyncPayload>) -> anyhow::Result<()> {
+        let src = msg.src.clone();
+        let synced = self.anti_entropy.receive(msg).await;
+        self.scheduler.record_new(synced.new);
+        self.known_messages
+            .lock()
+            .expect("Unable to get lock")
+            .entry(src)
+            .or_default()
+            .extend(&synced.peer_has);
+        Ok(())
+    }
+
+    async fn handle_topology(self, msg: Message<UpdateTopology>) -> anyhow::Result<()> {
+        self.topology.set_provided(&msg.body.payload.topology);
 
-        let reply = msg.reply(Payload::TopologyOk);
+        let reply = msg.reply(TopologyOk {});
         self.network.send(&reply).await;
         Ok(())
     }
 
-    fn handle_gossip_ok(&self, msg: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::GossipOk { messages } = msg.body.payload else {
-            panic!("expected gossip_ok");
+    async fn handle_gossip_ok(self, msg: Message<GossipOk>) -> anyhow::Result<()> {
+        let GossipOk { round } = msg.body.payload;
+        self.topology.record_ack(&msg.src);
+        let mut last_gossip = self.last_gossip.lock().expect("Unable to get lock");
+        let Some(sent) = last_gossip.get_mut(&msg.src) else {
+            return Ok(());
+        };
+        let Some(messages) = sent.unacked.remove(&round) else {
+            // Already acked, or forgotten as too old.
+            return Ok(());
         };
+        // What earlier rounds sent is either in this one or already known,
+        // so their acks are no longer needed.
+        sent.unacked = sent.unacked.split_off(&round);
         let mut known_message = self.known_messages.lock().expect("Unable to get lock");
         known_message
             .entry(msg.src.clone())
-            .and_modify(|v| v.extend(messages.clone()))
-            .or_insert(messages.clone());
+            .or_default()
+            .extend(&messages);
         Ok(())
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Broadcast {
-        message: usize,
-    },
-    BroadcastOk,
-    Read,
-    ReadOk {
-        messages: HashSet<usize>,
-    },
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
-    TopologyOk,
-    Gossip {
-        messages: HashSet<usize>,
-    },
-    GossipOk {
-        messages: HashSet<usize>,
-    },
+#[serde(tag = "type", rename = "broadcast")]
+struct Broadcast {
+    message: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "broadcast_ok")]
+struct BroadcastOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read")]
+struct Read {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk {
+    messages: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "topology")]
+struct UpdateTopology {
+    topology: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "topology_ok")]
+struct TopologyOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "gossip")]
+struct Gossip {
+    round: usize,
+    messages: RangeSet,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "gossip_ok")]
+struct GossipOk {
+    round: usize,
+}
+
+/// The gossip rounds sent to a peer, and the messages of those not acked yet.
+#[derive(Default)]
+struct SentGossip {
+    round: usize,
+    unacked: BTreeMap<usize, RangeSet>,
 }
@@ -0,0 +1,45 @@
+//! The echo and unique-ids workloads served by one binary.
+
+#[allow(dead_code)]
+#[path = "echo.rs"]
+mod echo;
+#[allow(dead_code)]
+#[path = "unique-ids.rs"]
+mod unique_ids;
+
+use echo::EchoNode;
+use gossip::{Message, Network, Node, Router, Runtime};
+use serde_json::Value;
+use std::sync::Arc;
+use unique_ids::UniqueIdsNode;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    Runtime::<Value, EchoUniqueIdsNode>::run().await
+}
+
+#[derive(Clone)]
+struct EchoUniqueIdsNode {
+    echo: EchoNode,
+    unique_ids: UniqueIdsNode,
+    router: Arc<Router<EchoUniqueIdsNode>>,
+}
+
+impl Node<Value> for EchoUniqueIdsNode {
+    fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
+        let router = Router::new()
+            .merge_with(EchoNode::routes(), |node: &Self| node.echo.clone())
+            .merge_with(UniqueIdsNode::routes(), |node: &Self| {
+                node.unique_ids.clone()
+            });
+        Self {
+            echo: EchoNode::from_init(id.clone(), neighbors.clone(), network.clone()),
+            unique_ids: UniqueIdsNode::from_init(id, neighbors, network),
+            router: Arc::new(router),
+        }
+    }
+
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
+    }
+}
@@ -1,36 +1,52 @@
-use gossip::{Message, Network, Node, Runtime};
+use gossip::{Message, Network, Node, Router, Runtime};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, EchoNode>::run().await
+    Runtime::<Value, EchoNode>::run().await
 }
 
 #[derive(Clone)]
-struct EchoNode {
+pub struct EchoNode {
     network: Network,
+    router: Arc<Router<EchoNode>>,
 }
 
-impl Node<Payload> for EchoNode {
-    fn from_init(_id: String, _neighbors: Vec<String>, network: Network) -> Self {
-        Self { network }
+impl EchoNode {
+    pub fn routes() -> Router<Self> {
+        Router::new().route("echo", Self::handle_echo)
+    }
+
+    async fn handle_echo(self, message: Message<Echo>) -> anyhow::Result<()> {
+        let echo = message.body.payload.echo.clone();
+        self.network.send(&message.reply(EchoOk { echo })).await;
+        Ok(())
     }
+}
 
-    async fn handle_message(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        if let Payload::Echo { echo } = message.get_payload() {
-            self.network
-                .send(&message.reply(Payload::EchoOk { echo: echo.clone() }))
-                .await
+impl Node<Value> for EchoNode {
+    fn from_init(_id: String, _neighbors: Vec<String>, network: Network) -> Self {
+        Self {
+            network,
+            router: Arc::new(Self::routes()),
         }
+    }
 
-        Ok(())
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Echo { echo: String },
-    EchoOk { echo: String },
+#[serde(tag = "type", rename = "echo")]
+struct Echo {
+    echo: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "echo_ok")]
+struct EchoOk {
+    echo: String,
 }
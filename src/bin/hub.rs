@@ -0,0 +1,182 @@
+//! Connects node processes into a cluster on one host.
+//!
+//! Nodes started with `GOSSIP_CONNECT` set to the hub's address connect to
+//! it, and the first connections become the nodes named by `GOSSIP_NODES`
+//! (`n0,n1,n2` by default), in order. Once all of them are connected and
+//! have answered `init`, the hub starts reading requests for the nodes from
+//! stdin. Every message is forwarded by its `dest`, and messages for anyone
+//! but the nodes, like replies to clients, are written to stdout. The Maelstrom key/value services are
+//! not provided. Listens on `GOSSIP_LISTEN`, `host:port` (`127.0.0.1:7000`
+//! by default) or `unix:/path`.
+
+use anyhow::bail;
+#[cfg(unix)]
+use gossip::UnixTransport;
+use gossip::{
+    init_logging, Message, StdioTransport, TcpTransport, Transport, TransportReader,
+    TransportWriter,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{channel, Sender},
+};
+use tracing::{debug, info, warn};
+
+/// The `src` of the `init` messages, whose replies the hub consumes.
+const HUB_ID: &str = "hub";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    init_logging();
+    let addr = std::env::var("GOSSIP_LISTEN").unwrap_or_else(|_| "127.0.0.1:7000".to_string());
+    let node_ids: Vec<String> = std::env::var("GOSSIP_NODES")
+        .unwrap_or_else(|_| "n0,n1,n2".to_string())
+        .split(',')
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect();
+
+    let listener = Listener::bind(&addr).await?;
+    info!(%addr, ?node_ids, "waiting for nodes to connect");
+    let (wire_tx, mut wire_rx) = channel::<String>(1024);
+    let mut nodes = HashMap::new();
+    for (msg_id, node_id) in node_ids.iter().enumerate() {
+        let node = listener.accept(wire_tx.clone()).await?;
+        info!(%node_id, "node connected");
+        let init = json!({
+            "src": HUB_ID,
+            "dest": node_id,
+            "body": {
+                "type": "init",
+                "msg_id": msg_id,
+                "node_id": node_id,
+                "node_ids": node_ids,
+            },
+        });
+        node.send(init.to_string()).await?;
+        nodes.insert(node_id.clone(), node);
+    }
+    // Only the connections feed the wire from now on, so it closes once
+    // every node has disconnected.
+    drop(wire_tx);
+
+    let (mut stdin, mut stdout) = StdioTransport::stdio().split();
+    // Nodes may already talk to each other while the others initialize.
+    let mut initializing: HashSet<&String> = node_ids.iter().collect();
+    while !initializing.is_empty() {
+        let Some(line) = wire_rx.recv().await else {
+            bail!("Nodes disconnected before {initializing:?} initialized");
+        };
+        if let Some(node_id) = forward(&nodes, &mut stdout, line).await? {
+            initializing.remove(&node_id);
+        }
+    }
+    info!("all nodes initialized");
+
+    loop {
+        let line = tokio::select! {
+            line = stdin.recv() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            Some(line) = wire_rx.recv() => line,
+        };
+        forward(&nodes, &mut stdout, line).await?;
+    }
+    info!("input closed, shutting down");
+    Ok(())
+}
+
+/// Sends `line` on to its `dest`. Returns the node a reply to the hub's
+/// `init` came from.
+async fn forward<TWriter: TransportWriter>(
+    nodes: &HashMap<String, Sender<String>>,
+    stdout: &mut TWriter,
+    line: String,
+) -> anyhow::Result<Option<String>> {
+    let Ok(msg) = serde_json::from_str::<Message<Value>>(&line) else {
+        warn!(%line, "dropping malformed message");
+        return Ok(None);
+    };
+    match nodes.get(&msg.dest) {
+        Some(node) => {
+            if node.send(line).await.is_err() {
+                warn!(dest = %msg.dest, "node disconnected, dropping message");
+            }
+        }
+        None if msg.dest == HUB_ID => match msg.get_payload()["type"].as_str() {
+            Some("init_ok") => {
+                debug!(src = %msg.src, "node initialized");
+                return Ok(Some(msg.src));
+            }
+            _ => bail!("{} failed to initialize: {}", msg.src, msg.get_payload()),
+        },
+        None => {
+            stdout.send(line).await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(None)
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn bind(addr: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            // A socket file left behind by an earlier run would fail the bind.
+            let _ = std::fs::remove_file(path);
+            return Ok(Self::Unix(UnixListener::bind(path)?));
+        }
+        Ok(Self::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    /// Accepts the next node, forwards the lines it sends to `wire`, and
+    /// returns where to send lines for it.
+    async fn accept(&self, wire: Sender<String>) -> io::Result<Sender<String>> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(connect(TcpTransport::from_stream(stream), wire))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(connect(UnixTransport::from_stream(stream), wire))
+            }
+        }
+    }
+}
+
+fn connect<TTransport: Transport>(transport: TTransport, wire: Sender<String>) -> Sender<String> {
+    let (mut reader, mut writer) = transport.split();
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = reader.recv().await {
+            if wire.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let (outbox_tx, mut outbox_rx) = channel::<String>(1024);
+    tokio::spawn(async move {
+        while let Some(line) = outbox_rx.recv().await {
+            if writer.send(line).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    outbox_tx
+}
@@ -1,18 +1,36 @@
-use gossip::{retry, KeyValueStore, Message, Network, Node, RpcError, Runtime, Storable, Storage};
+use gossip::{
+    retry, ErrorCode, KeyValueStore, MaelstromError, Message, Network, Node, Router, RpcError,
+    Runtime, RuntimeConfig, Storable, Storage, Timers,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::Duration};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::sync::{mpsc, oneshot};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, KafkaNode>::run().await
+    // A retried send must not append its message twice, so requests are deduplicated
+    // unless GOSSIP_DEDUP_WINDOW says otherwise.
+    let mut config = RuntimeConfig::from_env();
+    if std::env::var_os("GOSSIP_DEDUP_WINDOW").is_none() {
+        config.dedup_window = 4096;
+    }
+    Runtime::<Value, KafkaNode>::run_with_config(config).await
 }
 
 #[derive(Clone)]
-struct KafkaNode {
+pub struct KafkaNode {
     network: Network,
     logs: Logs,
     offsets: Offsets,
+    router: Arc<Router<KafkaNode>>,
 }
 
 struct CacheActor<T: Storable> {
@@ -41,15 +59,16 @@ struct CacheHandle<T: Storable> {
     sender: mpsc::Sender<CacheMessage<T>>,
 }
 impl<T: Storable + Default> CacheHandle<T> {
-    fn new() -> Self {
+    fn new(timers: &Timers) -> Self {
         let (tx, rx) = mpsc::channel(10);
         let cache = CacheActor {
             incoming: rx,
             store: Default::default(),
         };
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             cache.start().await;
         });
+        timers.track(task.abort_handle());
 
         Self { sender: tx }
     }
@@ -88,70 +107,97 @@ enum CacheMessage<T> {
     },
 }
 
+/// How many times an append re-reads the log and tries again.
+const MAX_APPEND_ATTEMPTS: usize = 10;
+
+/// One log entry. The token names the append that wrote it, so an append
+/// whose outcome is unknown can tell whether it landed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Entry {
+    msg: usize,
+    token: (String, u64),
+}
+
 #[derive(Clone, Debug)]
 struct Logs {
-    storage: KeyValueStore<Vec<usize>>,
-    cache: CacheHandle<Vec<usize>>,
+    node_id: String,
+    appends: Arc<AtomicU64>,
+    storage: KeyValueStore<Vec<Entry>>,
+    cache: CacheHandle<Vec<Entry>>,
 }
 
 impl Logs {
     fn new(network: Network, node_id: String) -> Self {
-        let storage = KeyValueStore::new("seq-kv", network.clone(), node_id);
+        let cache = CacheHandle::new(network.timers());
+        let storage = KeyValueStore::new("seq-kv", network, node_id.clone());
         Self {
-            storage: storage.clone(),
-            cache: CacheHandle::new(),
+            node_id,
+            appends: Default::default(),
+            storage,
+            cache,
         }
     }
-    async fn append(&self, key: String, value: usize) -> anyhow::Result<(usize, Vec<usize>)> {
-        let log_key = self.to_log_key(key.clone());
-        let current_log = self.get(&key).await?;
-        let offset = current_log.len();
-        let mut new_log = current_log.clone();
-        new_log.push(value);
-        self.storage
-            .cas(log_key, current_log, new_log.clone())
-            .await?;
-        Ok((offset, new_log))
-    }
-    async fn try_append(&self, key: String, value: usize) -> usize {
+
+    /// Appends `msg` to the log of `key` and returns its offset. A cas that
+    /// times out may still have landed, so every failed attempt re-reads the
+    /// log and looks for the append's token before trying again.
+    async fn try_append(&self, key: String, msg: usize) -> Result<usize, MaelstromError> {
         let log_key = self.to_log_key(key.clone());
-        let current_log = self.get_cached(&key).await;
-        let offset = current_log.len();
-        let mut new_log = current_log.clone();
-        new_log.push(value);
-        let (offset, new_log) = match self
-            .storage
-            .cas(log_key, current_log, new_log.clone())
-            .await
-        {
-            Ok(_) => (offset, new_log),
-            Err(_) => retry(
-                || self.append(key.clone(), value),
-                10,
-                Duration::from_millis(1),
-            )
-            .await
-            .expect("Couldn't commit after 10 retries"),
-        };
-        self.cache.set(key, new_log).await;
-        offset
+        let token = (
+            self.node_id.clone(),
+            self.appends.fetch_add(1, Ordering::Relaxed),
+        );
+        let mut current_log = self.get_cached(&key).await;
+        for _ in 0..MAX_APPEND_ATTEMPTS {
+            let offset = current_log.len();
+            let mut new_log = current_log.clone();
+            new_log.push(Entry {
+                msg,
+                token: token.clone(),
+            });
+            match self
+                .storage
+                .cas(log_key.clone(), current_log, new_log.clone())
+                .await
+            {
+                Ok(()) => {
+                    self.cache.set(key, new_log).await;
+                    return Ok(offset);
+                }
+                // Another append got there first, or ours landed unanswered.
+                Err(RpcError::CasFail | RpcError::Timeout) => {}
+                Err(e) => return Err(e.into()),
+            }
+            current_log = self.get(&key).await?;
+            if let Some(offset) = current_log.iter().position(|entry| entry.token == token) {
+                self.cache.set(key, current_log).await;
+                return Ok(offset);
+            }
+        }
+        Err(MaelstromError::new(
+            ErrorCode::TemporarilyUnavailable,
+            format!("couldn't append to {key} after {MAX_APPEND_ATTEMPTS} attempts"),
+        ))
     }
     async fn get_from_offset(&self, key: &str, offset: usize) -> anyhow::Result<Vec<usize>> {
         let current_log = self.get_cached(key).await;
-        Ok(current_log.iter().skip(offset).copied().collect())
+        Ok(current_log
+            .iter()
+            .skip(offset)
+            .map(|entry| entry.msg)
+            .collect())
     }
-    async fn get_cached(&self, key: &str) -> Vec<usize> {
+    async fn get_cached(&self, key: &str) -> Vec<Entry> {
         self.cache.get(key).await
     }
-    async fn get(&self, key: &str) -> anyhow::Result<Vec<usize>> {
-        Ok(self
-            .storage
+    async fn get(&self, key: &str) -> Result<Vec<Entry>, RpcError> {
+        self.storage
             .get(self.to_log_key(key.to_owned()))
             .await
             .or_else(|e| match e {
                 RpcError::KeyDoesNotExist => Ok(vec![]),
                 e => Err(e),
-            })?)
+            })
     }
     fn to_log_key(&self, key: String) -> String {
         "log-".to_owned() + &key
@@ -198,20 +244,14 @@ impl Offsets {
 }
 
 impl KafkaNode {
-    async fn handle_send(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Send { msg, key } = message.get_payload() else {
-            panic!("Incorrect message type");
-        };
-        let offset = self.logs.try_append(key.clone(), *msg).await;
-        self.network
-            .send(&message.reply(Payload::SendOk { offset }))
-            .await;
+    async fn handle_send(self, message: Message<SendMessage>) -> anyhow::Result<()> {
+        let SendMessage { msg, key } = message.get_payload();
+        let offset = self.logs.try_append(key.clone(), *msg).await?;
+        self.network.send(&message.reply(SendOk { offset })).await;
         Ok(())
     }
-    async fn handle_poll(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::Poll { offsets } = message.get_payload() else {
-            panic!("Incorrect message type");
-        };
+    async fn handle_poll(self, message: Message<Poll>) -> anyhow::Result<()> {
+        let Poll { offsets } = message.get_payload();
 
         let mut msgs: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
         for (key, offset) in offsets {
@@ -225,15 +265,11 @@ impl KafkaNode {
                     .collect(),
             );
         }
-        self.network
-            .send(&message.reply(Payload::PollOk { msgs }))
-            .await;
+        self.network.send(&message.reply(PollOk { msgs })).await;
         Ok(())
     }
-    async fn handle_commit_offsets(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::CommitOffsets { offsets } = message.get_payload() else {
-            panic!("Incorrect message type");
-        };
+    async fn handle_commit_offsets(self, message: Message<CommitOffsets>) -> anyhow::Result<()> {
+        let CommitOffsets { offsets } = message.get_payload();
 
         for (key, offset) in offsets {
             retry(
@@ -243,77 +279,88 @@ impl KafkaNode {
             )
             .await?;
         }
-        self.network
-            .send(&message.reply(Payload::CommitOffsetsOk))
-            .await;
+        self.network.send(&message.reply(CommitOffsetsOk {})).await;
         Ok(())
     }
-    async fn handle_list_committed_offsets(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        let Payload::ListCommittedOffsets { keys } = message.get_payload() else {
-            panic!("Incorrect message type");
-        };
+    async fn handle_list_committed_offsets(
+        self,
+        message: Message<ListCommittedOffsets>,
+    ) -> anyhow::Result<()> {
+        let ListCommittedOffsets { keys } = message.get_payload();
         let offsets = self.offsets.list(keys).await?;
         self.network
-            .send(&message.reply(Payload::ListCommittedOffsetsOk { offsets }))
+            .send(&message.reply(ListCommittedOffsetsOk { offsets }))
             .await;
         Ok(())
     }
 }
 
-impl Node<Payload> for KafkaNode {
+impl Node<Value> for KafkaNode {
     fn from_init(id: String, _neighbors: Vec<String>, network: Network) -> Self {
+        let router = Router::new()
+            .route("send", Self::handle_send)
+            .route("poll", Self::handle_poll)
+            .route("commit_offsets", Self::handle_commit_offsets)
+            .route(
+                "list_committed_offsets",
+                Self::handle_list_committed_offsets,
+            );
         Self {
             network: network.clone(),
             logs: Logs::new(network.clone(), id.clone()),
             offsets: Offsets::new(network.clone(), id),
+            router: Arc::new(router),
         }
     }
 
-    async fn handle_message(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        match message.get_payload() {
-            Payload::Send { .. } => self.handle_send(message).await?,
-            Payload::Poll { .. } => self.handle_poll(message).await?,
-            Payload::CommitOffsets { .. } => self.handle_commit_offsets(message).await?,
-            Payload::ListCommittedOffsets { .. } => {
-                self.handle_list_committed_offsets(message).await?
-            }
-            Payload::SendOk { .. }
-            | Payload::PollOk { .. }
-            | Payload::CommitOffsetsOk
-            | Payload::ListCommittedOffsetsOk { .. } => {
-                eprintln!("Received unexpected message {message:?}");
-            }
-        };
-
-        Ok(())
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Send {
-        msg: usize,
-        key: String,
-    },
-    SendOk {
-        offset: usize,
-    },
-    Poll {
-        offsets: HashMap<String, usize>,
-    },
-    PollOk {
-        msgs: HashMap<String, Vec<(usize, usize)>>,
-    },
-    CommitOffsets {
-        offsets: HashMap<String, usize>,
-    },
-    CommitOffsetsOk,
-    ListCommittedOffsets {
-        keys: Vec<String>,
-    },
-    ListCommittedOffsetsOk {
-        offsets: HashMap<String, usize>,
-    },
+#[serde(tag = "type", rename = "send")]
+struct SendMessage {
+    msg: usize,
+    key: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "send_ok")]
+struct SendOk {
+    offset: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "poll")]
+struct Poll {
+    offsets: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "poll_ok")]
+struct PollOk {
+    msgs: HashMap<String, Vec<(usize, usize)>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "commit_offsets")]
+struct CommitOffsets {
+    offsets: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "commit_offsets_ok")]
+struct CommitOffsetsOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "list_committed_offsets")]
+struct ListCommittedOffsets {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "list_committed_offsets_ok")]
+struct ListCommittedOffsetsOk {
+    offsets: HashMap<String, usize>,
 }
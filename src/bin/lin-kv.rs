@@ -0,0 +1,151 @@
+use gossip::{
+    ErrorCode, MaelstromError, Message, Network, Node, Raft, RaftConfig, Router, Runtime,
+    StateMachine,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    Runtime::<Value, LinKvNode>::run().await
+}
+
+#[derive(Clone)]
+pub struct LinKvNode {
+    network: Network,
+    raft: Raft<KvStore>,
+    router: Arc<Router<LinKvNode>>,
+}
+
+impl LinKvNode {
+    /// Every request goes through the log, reads included, so all of them
+    /// are linearizable.
+    async fn handle_request(self, msg: Message<Payload>) -> anyhow::Result<()> {
+        let reply = self.raft.propose(msg.body.payload.clone()).await??;
+        self.network.send(&msg.reply(reply)).await;
+        Ok(())
+    }
+
+    async fn handle_raft(self, msg: Message<Value>) -> anyhow::Result<()> {
+        self.raft.receive(msg).await
+    }
+}
+
+impl Node<Value> for LinKvNode {
+    fn from_init(_id: String, node_ids: Vec<String>, network: Network) -> Self {
+        let raft = Raft::start(
+            &network,
+            &node_ids,
+            KvStore::default(),
+            RaftConfig::default(),
+        );
+        let router = Router::new()
+            .route("read", Self::handle_request)
+            .route("write", Self::handle_request)
+            .route("cas", Self::handle_request)
+            .route("raft_vote", Self::handle_raft)
+            .route("raft_append", Self::handle_raft)
+            .route("raft_snapshot", Self::handle_raft)
+            .route("raft_propose", Self::handle_raft);
+        Self {
+            network,
+            raft,
+            router: Arc::new(router),
+        }
+    }
+
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
+    }
+}
+
+#[derive(Default)]
+struct KvStore {
+    values: HashMap<String, Value>,
+}
+
+impl StateMachine for KvStore {
+    type Command = Payload;
+    type Output = Result<Payload, MaelstromError>;
+    type Snapshot = HashMap<String, Value>;
+
+    fn apply(&mut self, command: Payload) -> Self::Output {
+        match command {
+            Payload::Read { key } => match self.values.get(&key.to_string()) {
+                Some(value) => Ok(Payload::ReadOk {
+                    value: value.clone(),
+                }),
+                None => Err(not_found(&key)),
+            },
+            Payload::Write { key, value } => {
+                self.values.insert(key.to_string(), value);
+                Ok(Payload::WriteOk)
+            }
+            Payload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => match self.values.get_mut(&key.to_string()) {
+                Some(value) if *value == from => {
+                    *value = to;
+                    Ok(Payload::CasOk)
+                }
+                Some(value) => Err(MaelstromError::new(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {from}, but had {value}"),
+                )),
+                None if create_if_not_exists => {
+                    self.values.insert(key.to_string(), to);
+                    Ok(Payload::CasOk)
+                }
+                None => Err(not_found(&key)),
+            },
+            _ => Err(MaelstromError::new(
+                ErrorCode::NotSupported,
+                "not a key-value request",
+            )),
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.values.clone()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        self.values = snapshot;
+    }
+}
+
+fn not_found(key: &Value) -> MaelstromError {
+    MaelstromError::new(
+        ErrorCode::KeyDoesNotExist,
+        format!("key {key} does not exist"),
+    )
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
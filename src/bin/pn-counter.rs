@@ -0,0 +1,77 @@
+use gossip::{CrdtGossip, Message, Network, Node, PNCounter, Replicated, Router, Runtime};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    Runtime::<Value, PNCounterNode>::run().await
+}
+
+#[derive(Clone)]
+struct PNCounterNode {
+    node_id: String,
+    network: Network,
+    counter: Replicated<PNCounter>,
+    router: Arc<Router<PNCounterNode>>,
+}
+
+impl PNCounterNode {
+    async fn handle_add(self, msg: Message<Add>) -> anyhow::Result<()> {
+        let Add { delta } = *msg.get_payload();
+        self.counter
+            .update(|counter| counter.add(&self.node_id, delta));
+        self.network.send(&msg.reply(AddOk {})).await;
+        Ok(())
+    }
+
+    async fn handle_read(self, msg: Message<Read>) -> anyhow::Result<()> {
+        let value = self.counter.get().value();
+        self.network.send(&msg.reply(ReadOk { value })).await;
+        Ok(())
+    }
+
+    async fn handle_gossip(self, msg: Message<CrdtGossip<PNCounter>>) -> anyhow::Result<()> {
+        self.counter.receive(&msg);
+        Ok(())
+    }
+}
+
+impl Node<Value> for PNCounterNode {
+    fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
+        let router = Router::new()
+            .route("add", Self::handle_add)
+            .route("read", Self::handle_read)
+            .route("crdt_gossip", Self::handle_gossip);
+        Self {
+            counter: Replicated::new(network.clone(), neighbors, Duration::from_millis(500)),
+            network,
+            node_id: id,
+            router: Arc::new(router),
+        }
+    }
+
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "add")]
+struct Add {
+    delta: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "add_ok")]
+struct AddOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read")]
+struct Read {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk {
+    value: i64,
+}
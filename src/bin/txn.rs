@@ -1,11 +1,21 @@
-use gossip::{Message, Network, Node, Runtime};
-use serde::{ser::SerializeTuple, Deserialize, Serialize};
-use std::{collections::HashMap, thread, vec};
+use gossip::{
+    Deliver, Message, Network, Node, Reliable, ReliableConfig, Router, Runtime, RuntimeConfig,
+    Timers,
+};
+use serde::{de::Unexpected, ser::SerializeTuple, Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc, vec};
 use tokio::sync::{mpsc, oneshot};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, TxnNode>::run().await
+    // A retried txn must not commit twice, so requests are deduplicated
+    // unless GOSSIP_DEDUP_WINDOW says otherwise.
+    let mut config = RuntimeConfig::from_env();
+    if std::env::var_os("GOSSIP_DEDUP_WINDOW").is_none() {
+        config.dedup_window = 4096;
+    }
+    Runtime::<Value, TxnNode>::run_with_config(config).await
 }
 
 #[derive(Clone)]
@@ -14,7 +24,7 @@ struct StoreActorHandle {
     history_sender: mpsc::Sender<Txn>,
 }
 impl StoreActorHandle {
-    fn new(network: Network, neighbors: Vec<String>, node_id: String) -> Self {
+    fn new(timers: &Timers, reliable: Reliable, neighbors: Vec<String>, node_id: String) -> Self {
         let (store_tx, store_rx) = mpsc::channel(100);
         let (txn_tx, txn_rx) = mpsc::channel(100);
         let handle = Self {
@@ -22,9 +32,11 @@ impl StoreActorHandle {
             history_sender: txn_tx,
         };
 
-        tokio::spawn(store_actor(store_rx));
+        let store = tokio::spawn(store_actor(store_rx));
+        timers.track(store.abort_handle());
 
-        tokio::spawn(replicator_actor(txn_rx, network, neighbors, node_id));
+        let replicator = tokio::spawn(replicator_actor(txn_rx, reliable, neighbors, node_id));
+        timers.track(replicator.abort_handle());
 
         handle
     }
@@ -38,11 +50,13 @@ impl StoreActorHandle {
         rx.await.unwrap()
     }
 
-    fn print(&self) {
-        let s = self.clone();
-        thread::spawn(move || {
-            s.store_sender.blocking_send(StoreMessage::Print).unwrap();
-        });
+    async fn print(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.store_sender
+            .send(StoreMessage::Print { done: tx })
+            .await
+            .unwrap();
+        rx.await.unwrap()
     }
 }
 
@@ -51,15 +65,18 @@ enum StoreMessage {
         txn: Txn,
         reply: oneshot::Sender<Txn>,
     },
-    Print,
+    Print {
+        done: oneshot::Sender<()>,
+    },
 }
 
 async fn store_actor(mut rx: mpsc::Receiver<StoreMessage>) {
     let mut store: HashMap<usize, usize> = HashMap::new();
     while let Some(msg) = rx.recv().await {
         match msg {
-            StoreMessage::Print => {
-                eprintln!("Store value: {store:?}");
+            StoreMessage::Print { done } => {
+                tracing::info!(?store, "store value");
+                let _ = done.send(());
             }
             StoreMessage::Commit { txn, reply } => {
                 let mut result = vec![];
@@ -85,7 +102,7 @@ async fn store_actor(mut rx: mpsc::Receiver<StoreMessage>) {
 
 async fn replicator_actor(
     mut rx: mpsc::Receiver<Txn>,
-    network: Network,
+    reliable: Reliable,
     neighbors: Vec<String>,
     node_id: String,
 ) {
@@ -94,61 +111,88 @@ async fn replicator_actor(
             if *node == node_id {
                 continue;
             }
-            let msg = Message::new(
-                node_id.clone(),
-                node.clone(),
-                Payload::Replicate { txn: txn.clone() },
-            );
-            network.send(&msg).await;
+            reliable.send(node, Replicate { txn: txn.clone() });
         }
     }
 }
 
 #[derive(Clone)]
-struct TxnNode {
+pub struct TxnNode {
     network: Network,
+    reliable: Reliable,
     store: StoreActorHandle,
+    router: Arc<Router<TxnNode>>,
 }
 
-impl Node<Payload> for TxnNode {
-    fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
-        let store = StoreActorHandle::new(network.clone(), neighbors, id);
-        Self { network, store }
+impl TxnNode {
+    async fn handle_txn(self, message: Message<TxnRequest>) -> anyhow::Result<()> {
+        let txn = &message.body.payload.txn;
+        self.store.history_sender.send(txn.clone()).await.unwrap();
+        let reply_txn = self.store.commit(txn.clone()).await;
+        let reply = message.reply(TxnOk { txn: reply_txn });
+        self.network.send(&reply).await;
+        Ok(())
     }
 
-    async fn handle_message(&self, message: Message<Payload>) -> anyhow::Result<()> {
-        match message.get_payload() {
-            Payload::Txn { txn } => {
-                self.store.history_sender.send(txn.clone()).await.unwrap();
-                let reply_txn = self.store.commit(txn.clone()).await;
-                let reply = message.reply(Payload::TxnOk { txn: reply_txn });
-                self.network.send(&reply).await
-            }
-            Payload::Replicate { txn } => {
-                self.store.commit(txn.clone()).await;
-            }
-            Payload::ReplicateOk | Payload::TxnOk { .. } => {
-                eprintln!("Received unexpected message: {message:?}");
-            }
-        }
+    async fn handle_reliable(self, message: Message<Deliver>) -> anyhow::Result<()> {
+        let node = self.clone();
+        self.reliable
+            .receive(message, move |msg| {
+                let node = node.clone();
+                async move { node.router.clone().dispatch(node, msg).await }
+            })
+            .await
+    }
+
+    async fn handle_replicate(self, message: Message<Replicate>) -> anyhow::Result<()> {
+        self.store.commit(message.body.payload.txn).await;
         Ok(())
     }
 }
 
-impl Drop for TxnNode {
-    fn drop(&mut self) {
-        self.store.print();
+impl Node<Value> for TxnNode {
+    fn from_init(id: String, neighbors: Vec<String>, network: Network) -> Self {
+        let reliable = Reliable::new(network.clone(), ReliableConfig::default());
+        let store = StoreActorHandle::new(network.timers(), reliable.clone(), neighbors, id);
+        let router = Router::new()
+            .route("txn", Self::handle_txn)
+            .route("replicate", Self::handle_replicate)
+            .route("reliable", Self::handle_reliable);
+        Self {
+            network,
+            reliable,
+            store,
+            router: Arc::new(router),
+        }
+    }
+
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
     }
+
+    async fn on_shutdown(&self) -> anyhow::Result<()> {
+        self.store.print().await;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "txn")]
+struct TxnRequest {
+    txn: Txn,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "txn_ok")]
+struct TxnOk {
+    txn: Txn,
 }
 
+/// A committed txn, sent to every other node over the reliable channel.
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Txn { txn: Txn },
-    TxnOk { txn: Txn },
-    Replicate { txn: Txn },
-    ReplicateOk,
+#[serde(tag = "type", rename = "replicate")]
+struct Replicate {
+    txn: Txn,
 }
 
 type Txn = Vec<Op>;
@@ -188,16 +232,16 @@ impl<'de> Deserialize<'de> for Op {
         D: serde::Deserializer<'de>,
     {
         let tuple: (char, usize, Option<usize>) = Deserialize::deserialize(deserializer)?;
-        match tuple.0 {
-            'r' => Ok(Op::Read {
-                key: tuple.1,
-                value: tuple.2,
-            }),
-            'w' => Ok(Op::Write {
-                key: tuple.1,
-                value: tuple.2.unwrap(),
-            }),
-            _ => panic!("Got unexpected op"),
+        match tuple {
+            ('r', key, value) => Ok(Op::Read { key, value }),
+            ('w', key, Some(value)) => Ok(Op::Write { key, value }),
+            ('w', key, None) => Err(serde::de::Error::custom(format!(
+                "write of key {key} has no value"
+            ))),
+            (op, _, _) => Err(serde::de::Error::invalid_value(
+                Unexpected::Char(op),
+                &"'r' or 'w'",
+            )),
         }
     }
 }
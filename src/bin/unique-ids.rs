@@ -1,48 +1,64 @@
-use gossip::{Network, Node, Runtime};
+use gossip::{Message, Network, Node, Router, Runtime};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    Runtime::<Payload, UniqueIdsNode>::run().await
+    Runtime::<Value, UniqueIdsNode>::run().await
 }
 
 #[derive(Clone)]
-struct UniqueIdsNode {
+pub struct UniqueIdsNode {
     node_id: String,
     network: Network,
+    next_id: Arc<AtomicUsize>,
+    router: Arc<Router<UniqueIdsNode>>,
 }
-impl Node<Payload> for UniqueIdsNode {
+
+impl UniqueIdsNode {
+    pub fn routes() -> Router<Self> {
+        Router::new().route("generate", Self::handle_generate)
+    }
+
+    async fn handle_generate(self, message: Message<Generate>) -> anyhow::Result<()> {
+        // Node ids are unique across the cluster, so a per-node counter is
+        // enough to keep generated ids unique.
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let node_id = &self.node_id;
+        self.network
+            .send(&message.reply(GenerateOk {
+                id: format!("{node_id}-{id}"),
+            }))
+            .await;
+        Ok(())
+    }
+}
+
+impl Node<Value> for UniqueIdsNode {
     fn from_init(id: String, _neighbors: Vec<String>, network: Network) -> Self {
         Self {
             node_id: id,
             network,
+            next_id: Default::default(),
+            router: Arc::new(Self::routes()),
         }
     }
 
-    async fn handle_message(&self, message: gossip::Message<Payload>) -> anyhow::Result<()> {
-        match message.get_payload() {
-            Payload::Generate => {
-                let msg_id: usize = message
-                    .body
-                    .msg_id
-                    .expect("msg_id should be available in generate message");
-                let node_id = &self.node_id;
-                self.network
-                    .send(&message.reply(Payload::GenerateOk {
-                        id: format!("{node_id}-{msg_id}"),
-                    }))
-                    .await
-            }
-            Payload::GenerateOk { .. } => {}
-        }
-        Ok(())
+    async fn handle_message(&self, message: Message<Value>) -> anyhow::Result<()> {
+        self.router.dispatch(self.clone(), message).await
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
-    Generate,
-    GenerateOk { id: String },
+#[serde(tag = "type", rename = "generate")]
+struct Generate {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "generate_ok")]
+struct GenerateOk {
+    id: String,
 }
@@ -0,0 +1,560 @@
+use crate::{Message, Network, Payload};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A state-based CRDT. `merge` must be commutative, associative and
+/// idempotent, so replicas that have seen the same states in any order and
+/// any number of times agree.
+pub trait Crdt: Payload + Default + Sync {
+    fn merge(&mut self, other: &Self);
+}
+
+/// Bounds for the elements of the set and register CRDTs.
+pub trait Element:
+    Clone + Debug + Ord + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+impl<E> Element for E where
+    E: Clone + Debug + Ord + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+/// Counts per node, merged by taking the larger count of each node.
+pub type VersionVector = BTreeMap<String, u64>;
+
+fn merge_max(into: &mut VersionVector, other: &VersionVector) {
+    for (node, count) in other {
+        let entry = into.entry(node.clone()).or_default();
+        *entry = (*entry).max(*count);
+    }
+}
+
+fn dominates(a: &VersionVector, b: &VersionVector) -> bool {
+    b.iter()
+        .all(|(node, count)| a.get(node).copied().unwrap_or_default() >= *count)
+}
+
+/// A counter that only grows. Each node counts its own increments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GCounter {
+    counts: VersionVector,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, node: &str, by: u64) {
+        *self.counts.entry(node.to_string()).or_default() += by;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        merge_max(&mut self.counts, &other.counts);
+    }
+}
+
+/// A counter that can go up and down, as a pair of grow-only counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(node, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PNCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// A set that only grows.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct GSet<T: Element> {
+    items: BTreeSet<T>,
+}
+
+impl<T: Element> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            items: Default::default(),
+        }
+    }
+}
+
+impl<T: Element> GSet<T> {
+    pub fn insert(&mut self, item: T) {
+        self.items.insert(item);
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn items(&self) -> &BTreeSet<T> {
+        &self.items
+    }
+}
+
+impl<T: Element> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.items.extend(other.items.iter().cloned());
+    }
+}
+
+/// A set whose items can be removed, but never added back.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct TwoPSet<T: Element> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Element> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            added: Default::default(),
+            removed: Default::default(),
+        }
+    }
+}
+
+impl<T: Element> TwoPSet<T> {
+    pub fn insert(&mut self, item: T) {
+        self.added.insert(item);
+    }
+
+    pub fn remove(&mut self, item: T) {
+        self.removed.insert(item);
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.added.contains(item) && !self.removed.contains(item)
+    }
+
+    pub fn items(&self) -> BTreeSet<T> {
+        self.added
+            .items()
+            .difference(self.removed.items())
+            .cloned()
+            .collect()
+    }
+}
+
+impl<T: Element> Crdt for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+}
+
+/// A unique tag for one insert: the inserting node and its insert count.
+type Dot = (String, u64);
+
+/// An observed-remove set. A remove only cancels the inserts it has seen, so
+/// an insert concurrent with a remove wins and items can be added back.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct ORSet<T: Element> {
+    clock: VersionVector,
+    inserts: BTreeSet<(T, Dot)>,
+    removed: BTreeSet<Dot>,
+}
+
+impl<T: Element> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            clock: Default::default(),
+            inserts: Default::default(),
+            removed: Default::default(),
+        }
+    }
+}
+
+impl<T: Element> ORSet<T> {
+    pub fn insert(&mut self, node: &str, item: T) {
+        let count = self.clock.entry(node.to_string()).or_default();
+        *count += 1;
+        self.inserts.insert((item, (node.to_string(), *count)));
+    }
+
+    pub fn remove(&mut self, item: &T) {
+        let dots = self
+            .inserts
+            .iter()
+            .filter(|(i, _)| i == item)
+            .map(|(_, dot)| dot.clone());
+        self.removed.extend(dots.collect::<Vec<_>>());
+        self.inserts.retain(|(i, _)| i != item);
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.inserts.iter().any(|(i, _)| i == item)
+    }
+
+    pub fn items(&self) -> BTreeSet<T> {
+        self.inserts.iter().map(|(item, _)| item.clone()).collect()
+    }
+}
+
+impl<T: Element> Crdt for ORSet<T> {
+    fn merge(&mut self, other: &Self) {
+        merge_max(&mut self.clock, &other.clock);
+        self.removed.extend(other.removed.iter().cloned());
+        self.inserts.extend(other.inserts.iter().cloned());
+        let removed = &self.removed;
+        self.inserts.retain(|(_, dot)| !removed.contains(dot));
+    }
+}
+
+/// A register where the last write wins. Writes are ordered by a Lamport
+/// timestamp, with ties broken by node id, so no clocks need to agree.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct LWWRegister<T: Element> {
+    value: Option<T>,
+    timestamp: u64,
+    node: String,
+}
+
+impl<T: Element> Default for LWWRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: 0,
+            node: String::new(),
+        }
+    }
+}
+
+impl<T: Element> LWWRegister<T> {
+    pub fn set(&mut self, node: &str, value: T) {
+        self.value = Some(value);
+        self.timestamp += 1;
+        self.node = node.to_string();
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+impl<T: Element> Crdt for LWWRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if (other.timestamp, &other.node) > (self.timestamp, &self.node) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// A multi-value register. A write replaces the values it has seen, and
+/// concurrent writes are all kept until a later write replaces them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct MVRegister<T: Element> {
+    values: Vec<(VersionVector, T)>,
+}
+
+impl<T: Element> Default for MVRegister<T> {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+        }
+    }
+}
+
+impl<T: Element> MVRegister<T> {
+    pub fn set(&mut self, node: &str, value: T) {
+        let mut version = VersionVector::new();
+        for (seen, _) in &self.values {
+            merge_max(&mut version, seen);
+        }
+        *version.entry(node.to_string()).or_default() += 1;
+        self.values = vec![(version, value)];
+    }
+
+    /// The concurrently written values, empty before the first write.
+    pub fn get(&self) -> Vec<&T> {
+        self.values.iter().map(|(_, value)| value).collect()
+    }
+}
+
+impl<T: Element> Crdt for MVRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        let mut values: Vec<(VersionVector, T)> = Vec::new();
+        for candidate in self.values.iter().chain(&other.values) {
+            let superseded =
+                self.values.iter().chain(&other.values).any(|(version, _)| {
+                    dominates(version, &candidate.0) && *version != candidate.0
+                });
+            if !superseded && !values.contains(candidate) {
+                values.push(candidate.clone());
+            }
+        }
+        values.sort();
+        self.values = values;
+    }
+}
+
+/// The full state of a replicated CRDT, gossiped between nodes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "crdt_gossip")]
+pub struct CrdtGossip<T> {
+    pub state: T,
+}
+
+/// A CRDT replicated across nodes by periodically gossiping its full state
+/// to every peer. Nodes route `crdt_gossip` messages to `receive`; since
+/// merging is idempotent, lost or repeated gossip is harmless.
+#[derive(Clone, Debug)]
+pub struct Replicated<T: Crdt> {
+    state: Arc<Mutex<T>>,
+}
+
+impl<T: Crdt> Replicated<T> {
+    pub fn new(network: Network, peers: Vec<String>, interval: Duration) -> Self {
+        let state: Arc<Mutex<T>> = Default::default();
+        let replicated = Self {
+            state: state.clone(),
+        };
+        let timers = network.timers().clone();
+        timers.every(interval, interval / 10, move || {
+            let gossip = CrdtGossip {
+                state: replicated.get(),
+            };
+            let network = network.clone();
+            let peers = peers.clone();
+            async move {
+                for peer in peers.iter().filter(|peer| *peer != network.node_id()) {
+                    let msg =
+                        Message::new(network.node_id().to_string(), peer.clone(), gossip.clone());
+                    network.send(&msg).await;
+                }
+            }
+        });
+        Self { state }
+    }
+
+    /// Applies a local change, which reaches the peers with the next gossip.
+    pub fn update<R>(&self, change: impl FnOnce(&mut T) -> R) -> R {
+        change(&mut self.state.lock().expect("Unable to lock crdt"))
+    }
+
+    pub fn get(&self) -> T {
+        self.state.lock().expect("Unable to lock crdt").clone()
+    }
+
+    pub fn receive(&self, msg: &Message<CrdtGossip<T>>) {
+        self.update(|state| state.merge(&msg.body.payload.state));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that merging `a`, `b` and `c` gives the same state in any
+    /// grouping and order, and that merging a state again changes nothing.
+    fn assert_merge_laws<T: Crdt + Clone + PartialEq + Debug>(a: &T, b: &T, c: &T) {
+        let merged = |x: &T, y: &T| {
+            let mut x = x.clone();
+            x.merge(y);
+            x
+        };
+        assert_eq!(merged(a, b), merged(b, a), "merge is not commutative");
+        assert_eq!(
+            merged(&merged(a, b), c),
+            merged(a, &merged(b, c)),
+            "merge is not associative"
+        );
+        assert_eq!(merged(a, a), *a, "merge is not idempotent");
+        let all = merged(&merged(a, b), c);
+        assert_eq!(merged(&all, b), all, "merge is not idempotent");
+    }
+
+    #[test]
+    fn g_counters_merge() {
+        let (mut a, mut b, mut c) = (
+            GCounter::default(),
+            GCounter::default(),
+            GCounter::default(),
+        );
+        a.increment("n0", 3);
+        b.increment("n1", 2);
+        b.increment("n0", 1);
+        c.increment("n2", 5);
+        assert_merge_laws(&a, &b, &c);
+
+        a.merge(&b);
+        a.merge(&c);
+        assert_eq!(a.value(), 10);
+    }
+
+    #[test]
+    fn pn_counters_merge() {
+        let (mut a, mut b, mut c) = (
+            PNCounter::default(),
+            PNCounter::default(),
+            PNCounter::default(),
+        );
+        a.add("n0", 4);
+        b.add("n1", -3);
+        c.add("n2", 2);
+        c.add("n2", -1);
+        assert_merge_laws(&a, &b, &c);
+
+        a.merge(&b);
+        a.merge(&c);
+        assert_eq!(a.value(), 2);
+    }
+
+    #[test]
+    fn g_sets_merge() {
+        let (mut a, mut b, mut c) = (GSet::default(), GSet::default(), GSet::default());
+        a.insert(1);
+        b.insert(2);
+        b.insert(1);
+        c.insert(3);
+        assert_merge_laws(&a, &b, &c);
+
+        a.merge(&b);
+        a.merge(&c);
+        assert_eq!(a.items(), &BTreeSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn two_p_sets_merge_and_never_add_back() {
+        let (mut a, mut b, mut c) = (TwoPSet::default(), TwoPSet::default(), TwoPSet::default());
+        a.insert(1);
+        a.insert(2);
+        b.insert(2);
+        b.remove(2);
+        c.insert(3);
+        assert_merge_laws(&a, &b, &c);
+
+        a.merge(&b);
+        a.merge(&c);
+        assert_eq!(a.items(), BTreeSet::from([1, 3]));
+        a.insert(2);
+        assert!(!a.contains(&2));
+    }
+
+    #[test]
+    fn or_sets_merge() {
+        let (mut a, mut b, mut c) = (ORSet::default(), ORSet::default(), ORSet::default());
+        a.insert("n0", 1);
+        a.insert("n0", 2);
+        b.merge(&a);
+        b.remove(&1);
+        b.insert("n1", 3);
+        c.insert("n2", 1);
+        assert_merge_laws(&a, &b, &c);
+    }
+
+    #[test]
+    fn or_set_insert_wins_over_a_concurrent_remove() {
+        let (mut a, mut b) = (ORSet::default(), ORSet::default());
+        a.insert("n0", "x".to_string());
+        b.merge(&a);
+
+        // n1 removes the insert it saw while n0 inserts "x" again.
+        b.remove(&"x".to_string());
+        a.insert("n0", "x".to_string());
+        a.merge(&b);
+        b.merge(&a);
+        assert!(a.contains(&"x".to_string()));
+        assert_eq!(a, b);
+
+        // A remove that has seen every insert does remove the item.
+        b.remove(&"x".to_string());
+        a.merge(&b);
+        assert!(!a.contains(&"x".to_string()));
+    }
+
+    #[test]
+    fn lww_registers_merge() {
+        let (mut a, mut b, mut c) = (
+            LWWRegister::default(),
+            LWWRegister::default(),
+            LWWRegister::default(),
+        );
+        a.set("n0", 1);
+        b.set("n1", 2);
+        b.set("n1", 3);
+        c.set("n2", 4);
+        assert_merge_laws(&a, &b, &c);
+
+        a.merge(&b);
+        assert_eq!(a.get(), Some(&3));
+    }
+
+    #[test]
+    fn lww_register_breaks_timestamp_ties_by_node_id() {
+        let (mut a, mut b) = (LWWRegister::default(), LWWRegister::default());
+        a.set("n0", "from n0".to_string());
+        b.set("n1", "from n1".to_string());
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab.get(), Some(&"from n1".to_string()));
+        assert_eq!(ba.get(), Some(&"from n1".to_string()));
+    }
+
+    #[test]
+    fn mv_registers_merge() {
+        let (mut a, mut b, mut c) = (
+            MVRegister::default(),
+            MVRegister::default(),
+            MVRegister::default(),
+        );
+        a.set("n0", 1);
+        b.merge(&a);
+        b.set("n1", 2);
+        c.set("n2", 3);
+        assert_merge_laws(&a, &b, &c);
+    }
+
+    #[test]
+    fn mv_register_keeps_concurrent_writes_and_drops_superseded_ones() {
+        let (mut a, mut b) = (MVRegister::default(), MVRegister::default());
+        a.set("n0", 1);
+        b.merge(&a);
+
+        // Both overwrite 1 without seeing each other's write.
+        a.set("n0", 2);
+        b.set("n1", 3);
+        a.merge(&b);
+        let mut values = a.get();
+        values.sort();
+        assert_eq!(values, vec![&2, &3]);
+
+        // A write after the merge replaces both.
+        a.set("n0", 4);
+        b.merge(&a);
+        assert_eq!(b.get(), vec![&4]);
+    }
+}
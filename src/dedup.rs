@@ -0,0 +1,131 @@
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+type Key = (String, usize);
+
+/// Remembers the replies sent to the last `window` requests, keyed by the
+/// requester and its msg_id, so a redelivered request is answered from the
+/// cache instead of being handled twice.
+#[derive(Clone, Debug)]
+pub(crate) struct DedupCache {
+    window: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    states: HashMap<Key, Entry>,
+    order: VecDeque<Key>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    InFlight,
+    Replied(Value),
+}
+
+/// What to do with an inbound request.
+pub(crate) enum Seen {
+    /// First delivery; handle it and call `finish` once the handler is done.
+    New,
+    /// Still being handled; its reply will answer the duplicate too.
+    InFlight,
+    /// Already answered with this reply payload.
+    Replied(Value),
+}
+
+impl DedupCache {
+    pub(crate) fn new(window: usize) -> Self {
+        Self {
+            window,
+            entries: Default::default(),
+        }
+    }
+
+    pub(crate) fn check(&self, src: &str, msg_id: usize) -> Seen {
+        let mut entries = self.entries.lock().expect("Unable to lock dedup cache");
+        let key = (src.to_string(), msg_id);
+        match entries.states.get(&key) {
+            Some(Entry::InFlight) => return Seen::InFlight,
+            Some(Entry::Replied(reply)) => return Seen::Replied(reply.clone()),
+            None => {}
+        }
+
+        while entries.order.len() >= self.window {
+            let Some(oldest) = entries.order.pop_front() else {
+                break;
+            };
+            entries.states.remove(&oldest);
+        }
+        entries.states.insert(key.clone(), Entry::InFlight);
+        entries.order.push_back(key);
+        Seen::New
+    }
+
+    /// Records the reply to a request that is being handled. Replies to
+    /// requests the cache is not tracking are ignored.
+    pub(crate) fn record(&self, src: &str, msg_id: usize, reply: Value) {
+        let mut entries = self.entries.lock().expect("Unable to lock dedup cache");
+        if let Some(entry) = entries.states.get_mut(&(src.to_string(), msg_id)) {
+            if matches!(entry, Entry::InFlight) {
+                *entry = Entry::Replied(reply);
+            }
+        }
+    }
+
+    /// Forgets a request whose handler finished without replying, so a retry
+    /// of it is handled again rather than dropped.
+    pub(crate) fn finish(&self, src: &str, msg_id: usize) {
+        let mut entries = self.entries.lock().expect("Unable to lock dedup cache");
+        let key = (src.to_string(), msg_id);
+        if let Some(Entry::InFlight) = entries.states.get(&key) {
+            entries.states.remove(&key);
+            entries.order.retain(|k| *k != key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redelivered_requests_get_the_recorded_reply() {
+        let cache = DedupCache::new(8);
+        assert!(matches!(cache.check("c1", 1), Seen::New));
+        cache.record("c1", 1, json!({ "type": "send_ok", "offset": 3 }));
+        assert!(matches!(
+            cache.check("c1", 1),
+            Seen::Replied(reply) if reply["offset"] == 3
+        ));
+        // The msg_id is only unique per requester.
+        assert!(matches!(cache.check("c2", 1), Seen::New));
+    }
+
+    #[test]
+    fn duplicates_of_requests_in_flight_are_not_handled_again() {
+        let cache = DedupCache::new(8);
+        assert!(matches!(cache.check("c1", 1), Seen::New));
+        assert!(matches!(cache.check("c1", 1), Seen::InFlight));
+
+        // Without a reply there is nothing to replay, so a retry after the
+        // handler finished is handled again.
+        cache.finish("c1", 1);
+        assert!(matches!(cache.check("c1", 1), Seen::New));
+    }
+
+    #[test]
+    fn the_oldest_requests_are_evicted_from_the_window() {
+        let cache = DedupCache::new(2);
+        for msg_id in 1..=3 {
+            assert!(matches!(cache.check("c1", msg_id), Seen::New));
+            cache.record("c1", msg_id, json!({ "type": "echo_ok" }));
+        }
+        assert!(matches!(cache.check("c1", 3), Seen::Replied(_)));
+        assert!(matches!(cache.check("c1", 1), Seen::New));
+    }
+}
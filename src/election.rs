@@ -0,0 +1,224 @@
+use crate::{KeyValueStore, Network, RpcError, Storage};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::watch, time::Instant};
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct ElectionConfig {
+    /// How long a lease lasts without being renewed.
+    pub lease_duration: Duration,
+    /// How often the leader renews its lease, and followers check on it.
+    /// Also bounds every lin-kv call.
+    pub renew_interval: Duration,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Self {
+            lease_duration: Duration::from_millis(1500),
+            renew_interval: Duration::from_millis(300),
+        }
+    }
+}
+
+/// The lease as stored in lin-kv. Every renewal bumps `renewals`, so
+/// followers can tell a live lease from an abandoned one without comparing
+/// clocks across nodes.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct Lease {
+    leader: String,
+    term: u64,
+    renewals: u64,
+}
+
+/// Elects one leader per `key` through a lease in lin-kv.
+///
+/// The leader renews the lease with a CAS every `renew_interval`, and counts
+/// itself leader only until `lease_duration` after it sent its last
+/// successful renewal. A follower takes over with a CAS once it has seen
+/// the same lease for `lease_duration`, by which time a leader cut off by a
+/// partition has already stepped down, so there are never two leaders.
+#[derive(Clone, Debug)]
+pub struct LeaderElection {
+    node_id: String,
+    key: String,
+    config: ElectionConfig,
+    storage: KeyValueStore<Lease>,
+    state: Arc<Mutex<State>>,
+    leader: Arc<watch::Sender<Option<String>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The lease last read or written, and when it was first seen.
+    observed: Option<(Lease, Instant)>,
+    /// While we hold the lease, when it runs out.
+    held_until: Option<Instant>,
+}
+
+impl LeaderElection {
+    /// Starts campaigning for `key`, until the timers are cancelled.
+    pub fn start(network: &Network, key: impl Into<String>, config: ElectionConfig) -> Self {
+        let node_id = network.node_id().to_string();
+        let election = Self {
+            storage: KeyValueStore::new("lin-kv", network.clone(), node_id.clone()),
+            node_id,
+            key: key.into(),
+            config,
+            state: Default::default(),
+            leader: Arc::new(watch::channel(None).0),
+        };
+
+        let this = election.clone();
+        network.timers().every(
+            election.config.renew_interval,
+            election.config.renew_interval / 10,
+            move || {
+                let this = this.clone();
+                async move { this.tick().await }
+            },
+        );
+        election
+    }
+
+    /// The leader as far as this node knows. This node is only ever named
+    /// while it holds the lease; other nodes may have lost theirs since.
+    pub fn current_leader(&self) -> Option<String> {
+        let leader = self.leader.borrow().clone();
+        if leader.as_deref() == Some(self.node_id.as_str()) && !self.is_leader() {
+            return None;
+        }
+        leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        let state = self.state.lock().expect("Unable to lock election");
+        state
+            .held_until
+            .is_some_and(|held_until| Instant::now() < held_until)
+    }
+
+    /// The term of the lease last read or written, which a leader holds.
+    /// Terms only grow, and each has at most one leader.
+    pub fn term(&self) -> Option<u64> {
+        let state = self.state.lock().expect("Unable to lock election");
+        state.observed.as_ref().map(|(lease, _)| lease.term)
+    }
+
+    /// Leadership changes, as seen by `current_leader`. `None` while the
+    /// lease is vacant or unreachable.
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.leader.subscribe()
+    }
+
+    async fn tick(&self) {
+        let started = Instant::now();
+        if !self.is_leader() {
+            self.state
+                .lock()
+                .expect("Unable to lock election")
+                .held_until = None;
+        }
+
+        let lease = match self.bounded(self.storage.get(self.key.clone())).await {
+            Ok(lease) => Some(lease),
+            Err(RpcError::KeyDoesNotExist) => None,
+            Err(_) => {
+                self.publish(self.is_leader().then(|| self.node_id.clone()));
+                return;
+            }
+        };
+
+        let next = match &lease {
+            Some(lease) if lease.leader == self.node_id => Lease {
+                renewals: lease.renewals + 1,
+                ..lease.clone()
+            },
+            Some(lease) if self.observe(lease).elapsed() < self.config.lease_duration => {
+                self.publish(Some(lease.leader.clone()));
+                return;
+            }
+            Some(lease) => Lease {
+                leader: self.node_id.clone(),
+                term: lease.term + 1,
+                renewals: 0,
+            },
+            None => Lease {
+                leader: self.node_id.clone(),
+                term: 1,
+                renewals: 0,
+            },
+        };
+
+        // A missing key is created whatever `from` is.
+        let from = lease.clone().unwrap_or_else(|| next.clone());
+        let result = self
+            .bounded(self.storage.cas(self.key.clone(), from, next.clone()))
+            .await;
+        match result {
+            Ok(()) => {
+                let mut state = self.state.lock().expect("Unable to lock election");
+                state.held_until = Some(started + self.config.lease_duration);
+                state.observed = Some((next, Instant::now()));
+            }
+            Err(RpcError::CasFail) => {
+                // Someone else renewed or took over first.
+                self.state
+                    .lock()
+                    .expect("Unable to lock election")
+                    .held_until = None;
+            }
+            Err(_) => {}
+        }
+        if self.is_leader() {
+            self.publish(Some(self.node_id.clone()));
+        } else {
+            self.publish(
+                lease
+                    .filter(|lease| lease.leader != self.node_id)
+                    .map(|lease| lease.leader),
+            );
+        }
+    }
+
+    /// Records that `lease` was read just now, and returns when it was first
+    /// seen unchanged.
+    fn observe(&self, lease: &Lease) -> Instant {
+        let mut state = self.state.lock().expect("Unable to lock election");
+        match &state.observed {
+            Some((observed, since)) if observed == lease => *since,
+            _ => {
+                let now = Instant::now();
+                state.observed = Some((lease.clone(), now));
+                now
+            }
+        }
+    }
+
+    /// Gives up on `call` after a renew interval, so a slow lin-kv cannot
+    /// hold up stepping down.
+    async fn bounded<T>(
+        &self,
+        call: impl Future<Output = Result<T, RpcError>>,
+    ) -> Result<T, RpcError> {
+        tokio::time::timeout(self.config.renew_interval, call)
+            .await
+            .unwrap_or(Err(RpcError::Timeout))
+    }
+
+    fn publish(&self, leader: Option<String>) {
+        self.leader.send_if_modified(|current| {
+            if *current == leader {
+                return false;
+            }
+            info!(key = %self.key, ?leader, "leader changed");
+            *current = leader;
+            true
+        });
+    }
+}
@@ -1,9 +1,11 @@
+use serde::{Deserialize, Serialize};
 use std::{error::Error, fmt::Display};
 
 #[derive(Debug)]
 pub enum RpcError {
     KeyDoesNotExist,
     CasFail,
+    Timeout,
     Unknown(anyhow::Error),
 }
 
@@ -14,3 +16,127 @@ impl Display for RpcError {
         write!(f, "{self:?}")
     }
 }
+
+/// The standard error codes from the Maelstrom protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(usize),
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> usize {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => *code,
+        }
+    }
+
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(self.code() as u64)
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let code: usize = Deserialize::deserialize(deserializer)?;
+        Ok(ErrorCode::from_code(code))
+    }
+}
+
+/// A Maelstrom `error` body. Returning one from `Node::handle_message` makes
+/// the runtime send it back to the sender of the request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+}
+
+impl Error for MaelstromError {}
+
+impl Display for MaelstromError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} ({}): {}", self.code, self.code.code(), self.text)
+    }
+}
+
+impl From<RpcError> for MaelstromError {
+    fn from(error: RpcError) -> Self {
+        match error {
+            RpcError::KeyDoesNotExist => {
+                MaelstromError::new(ErrorCode::KeyDoesNotExist, "key does not exist")
+            }
+            RpcError::CasFail => {
+                MaelstromError::new(ErrorCode::PreconditionFailed, "compare and set failed")
+            }
+            RpcError::Timeout => MaelstromError::new(ErrorCode::Timeout, "rpc timed out"),
+            RpcError::Unknown(e) => MaelstromError::new(ErrorCode::Crash, e.to_string()),
+        }
+    }
+}
+
+impl From<MaelstromError> for RpcError {
+    fn from(error: MaelstromError) -> Self {
+        match error.code {
+            ErrorCode::KeyDoesNotExist => RpcError::KeyDoesNotExist,
+            ErrorCode::PreconditionFailed => RpcError::CasFail,
+            ErrorCode::Timeout => RpcError::Timeout,
+            _ => RpcError::Unknown(error.into()),
+        }
+    }
+}
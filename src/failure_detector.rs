@@ -0,0 +1,315 @@
+use crate::{Message, Network};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::broadcast, time::Instant};
+use tracing::info;
+
+/// What the failure detector believes about a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// No heartbeat has arrived yet, or there is no failure detector.
+    Unknown,
+    Alive,
+    /// Heartbeats stopped arriving; the peer is down or partitioned away.
+    Suspected,
+}
+
+/// A change in the status of `peer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerEvent {
+    pub peer: String,
+    pub status: PeerStatus,
+}
+
+#[derive(Clone, Debug)]
+pub struct FailureDetectorConfig {
+    pub heartbeat_interval: Duration,
+    /// The phi at which a peer is suspected. 8 means a false suspicion
+    /// about once in 10^8 heartbeats, were arrivals normally distributed.
+    pub phi_threshold: f64,
+    /// How many recent heartbeat intervals the estimate is based on.
+    pub window: usize,
+    /// Extra delay tolerated on top of the observed intervals, for GC pauses
+    /// and latency spikes.
+    pub acceptable_pause: Duration,
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_millis(500),
+            phi_threshold: 8.0,
+            window: 100,
+            acceptable_pause: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Namespaced so it can't collide with the message types of a workload.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "fd_heartbeat")]
+pub(crate) struct Heartbeat {}
+
+impl Heartbeat {
+    pub(crate) const TYPE: &'static str = "fd_heartbeat";
+}
+
+/// A phi-accrual failure detector. Every node sends heartbeats to all its
+/// peers; the time since a peer's last one is turned into a suspicion level,
+/// phi, given the intervals seen between its heartbeats so far.
+#[derive(Clone, Debug)]
+pub struct FailureDetector {
+    config: FailureDetectorConfig,
+    peers: Arc<Mutex<HashMap<String, History>>>,
+    events: broadcast::Sender<PeerEvent>,
+    sent: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+struct History {
+    last: Instant,
+    intervals: VecDeque<Duration>,
+    status: PeerStatus,
+}
+
+impl FailureDetector {
+    pub fn new(config: FailureDetectorConfig, peers: &[String]) -> Self {
+        let now = Instant::now();
+        // Until the first heartbeat, pretend one arrived at startup, so peers
+        // that never send any end up suspected too.
+        let peers = peers
+            .iter()
+            .map(|peer| {
+                let history = History {
+                    last: now,
+                    intervals: VecDeque::from([config.heartbeat_interval]),
+                    status: PeerStatus::Unknown,
+                };
+                (peer.clone(), history)
+            })
+            .collect();
+        let (events, _) = broadcast::channel(64);
+        Self {
+            config,
+            peers: Arc::new(Mutex::new(peers)),
+            events,
+            sent: Default::default(),
+        }
+    }
+
+    /// Sends heartbeats to every peer and re-evaluates them every heartbeat
+    /// interval, until the timers are cancelled.
+    pub fn start(&self, network: &Network) {
+        let this = self.clone();
+        let timers = network.timers().clone();
+        let network = network.clone();
+        let interval = self.config.heartbeat_interval;
+        timers.every(interval, Duration::ZERO, move || {
+            let this = this.clone();
+            let network = network.clone();
+            async move {
+                let peers: Vec<String> = this
+                    .peers
+                    .lock()
+                    .expect("Unable to lock peers")
+                    .keys()
+                    .cloned()
+                    .collect();
+                for peer in peers {
+                    let msg = Message::new(network.node_id().to_string(), peer, Heartbeat {});
+                    network.send(&msg).await;
+                    this.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                this.check();
+            }
+        });
+    }
+
+    pub fn status(&self, peer: &str) -> PeerStatus {
+        self.peers
+            .lock()
+            .expect("Unable to lock peers")
+            .get(peer)
+            .map_or(PeerStatus::Unknown, |history| history.status)
+    }
+
+    /// How many heartbeats this node has sent.
+    pub fn heartbeats_sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Status changes from now on. Slow receivers miss the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    /// The current suspicion level of `peer`.
+    pub fn phi(&self, peer: &str) -> f64 {
+        let peers = self.peers.lock().expect("Unable to lock peers");
+        peers
+            .get(peer)
+            .map_or(0.0, |history| self.phi_of(history, Instant::now()))
+    }
+
+    pub(crate) fn heartbeat(&self, peer: &str) {
+        let now = Instant::now();
+        let changed = {
+            let mut peers = self.peers.lock().expect("Unable to lock peers");
+            let Some(history) = peers.get_mut(peer) else {
+                return;
+            };
+            // The gap before a first or a late heartbeat says nothing about
+            // the usual intervals.
+            if history.status == PeerStatus::Alive {
+                history.intervals.push_back(now - history.last);
+                if history.intervals.len() > self.config.window {
+                    history.intervals.pop_front();
+                }
+            }
+            history.last = now;
+            std::mem::replace(&mut history.status, PeerStatus::Alive) != PeerStatus::Alive
+        };
+        if changed {
+            self.notify(peer, PeerStatus::Alive);
+        }
+    }
+
+    fn check(&self) {
+        let now = Instant::now();
+        let suspected: Vec<String> = {
+            let mut peers = self.peers.lock().expect("Unable to lock peers");
+            peers
+                .iter_mut()
+                .filter(|(_, history)| history.status != PeerStatus::Suspected)
+                .filter(|(_, history)| self.phi_of(history, now) >= self.config.phi_threshold)
+                .map(|(peer, history)| {
+                    history.status = PeerStatus::Suspected;
+                    peer.clone()
+                })
+                .collect()
+        };
+        for peer in suspected {
+            self.notify(&peer, PeerStatus::Suspected);
+        }
+    }
+
+    /// Approximates the normal distribution's tail with a logistic function,
+    /// as in Akka's implementation.
+    fn phi_of(&self, history: &History, now: Instant) -> f64 {
+        let count = history.intervals.len() as f64;
+        let mean = history
+            .intervals
+            .iter()
+            .map(Duration::as_secs_f64)
+            .sum::<f64>()
+            / count;
+        let variance = history
+            .intervals
+            .iter()
+            .map(|interval| (interval.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        let min_std_dev = self.config.heartbeat_interval.as_secs_f64() / 4.0;
+        let std_dev = variance.sqrt().max(min_std_dev);
+        let mean = mean + self.config.acceptable_pause.as_secs_f64();
+
+        let elapsed = (now - history.last).as_secs_f64();
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+
+    fn notify(&self, peer: &str, status: PeerStatus) {
+        info!(peer, ?status, "peer status changed");
+        let _ = self.events.send(PeerEvent {
+            peer: peer.to_string(),
+            status,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    fn detector() -> FailureDetector {
+        FailureDetector::new(FailureDetectorConfig::default(), &["n1".to_string()])
+    }
+
+    async fn heartbeats(detector: &FailureDetector, count: usize) {
+        for _ in 0..count {
+            advance(INTERVAL).await;
+            detector.heartbeat("n1");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn phi_grows_with_the_time_since_the_last_heartbeat() {
+        let detector = detector();
+        heartbeats(&detector, 10).await;
+
+        // Steady intervals of 500ms put the expected arrival, with the
+        // acceptable pause, at 1.5s, where phi is -log10(0.5).
+        advance(Duration::from_millis(1500)).await;
+        assert!((detector.phi("n1") - 0.5f64.log10().abs()).abs() < 0.01);
+
+        let mut last = detector.phi("n1");
+        for _ in 0..4 {
+            advance(Duration::from_millis(250)).await;
+            let phi = detector.phi("n1");
+            assert!(phi > last, "{phi} > {last}");
+            last = phi;
+        }
+        assert!(last >= FailureDetectorConfig::default().phi_threshold);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_are_suspected_when_heartbeats_stop_and_alive_when_they_resume() {
+        let detector = detector();
+        let mut events = detector.subscribe();
+        assert_eq!(detector.status("n1"), PeerStatus::Unknown);
+
+        heartbeats(&detector, 10).await;
+        detector.check();
+        assert_eq!(detector.status("n1"), PeerStatus::Alive);
+        assert_eq!(events.try_recv().unwrap().status, PeerStatus::Alive);
+
+        advance(Duration::from_millis(1500)).await;
+        detector.check();
+        assert_eq!(detector.status("n1"), PeerStatus::Alive);
+
+        advance(Duration::from_millis(1000)).await;
+        detector.check();
+        assert_eq!(detector.status("n1"), PeerStatus::Suspected);
+        assert_eq!(events.try_recv().unwrap().status, PeerStatus::Suspected);
+
+        detector.heartbeat("n1");
+        assert_eq!(detector.status("n1"), PeerStatus::Alive);
+        assert_eq!(events.try_recv().unwrap().status, PeerStatus::Alive);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_that_never_send_heartbeats_are_suspected() {
+        let detector = detector();
+        advance(Duration::from_secs(5)).await;
+        detector.check();
+        assert_eq!(detector.status("n1"), PeerStatus::Suspected);
+        assert_eq!(detector.phi("unknown"), 0.0);
+        assert_eq!(detector.status("unknown"), PeerStatus::Unknown);
+    }
+}
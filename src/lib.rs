@@ -1,16 +1,46 @@
+mod anti_entropy;
+mod crdt;
+mod dedup;
+mod election;
 mod errors;
+mod failure_detector;
+mod logging;
 mod message;
 mod network;
 mod node;
+mod raft;
+mod ranges;
+mod reliable;
+mod router;
 mod runtime;
+mod scheduler;
+#[cfg(feature = "simulator")]
+mod simulator;
 mod storage;
+mod timers;
+mod topology;
+mod transport;
 mod utils;
 
+pub use anti_entropy::*;
+pub use crdt::*;
+pub use election::*;
 pub use errors::*;
+pub use failure_detector::*;
+pub use logging::*;
 pub use message::*;
 pub use network::*;
 pub use node::*;
+pub use raft::*;
+pub use ranges::*;
+pub use reliable::*;
+pub use router::*;
 pub use runtime::*;
+pub use scheduler::*;
+#[cfg(feature = "simulator")]
+pub use simulator::*;
 pub use storage::*;
+pub use timers::*;
+pub use topology::*;
+pub use transport::*;
 pub use utils::*;
-
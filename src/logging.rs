@@ -0,0 +1,24 @@
+use tracing_subscriber::EnvFilter;
+
+/// Installs a `tracing` subscriber that writes to stderr, where Maelstrom
+/// collects node logs.
+///
+/// `GOSSIP_LOG` takes `EnvFilter` directives and defaults to `info`. Use
+/// `debug` to log every message in and out with its ids, and `trace` to add
+/// the full JSON bodies. `GOSSIP_LOG_FORMAT=json` writes one JSON object per
+/// line, including the fields of the enclosing spans.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_env("GOSSIP_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false);
+    let _ = match std::env::var("GOSSIP_LOG_FORMAT").as_deref() {
+        Ok("json") => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+        _ => builder.try_init(),
+    };
+}
@@ -5,6 +5,11 @@ use std::fmt::Debug;
 pub trait Payload: Clone + Debug + Serialize + DeserializeOwned + Send + 'static {}
 impl<P: Clone + Debug + Serialize + DeserializeOwned + Send + 'static> Payload for P {}
 
+/// A payload that is answered with a payload of type `Response`.
+pub trait Request: Payload {
+    type Response: Payload;
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Message<Payload> {
     pub src: String,
@@ -30,12 +35,14 @@ impl<T> Message<T> {
         self
     }
 
+    /// Addresses `payload` back to the sender of this message. The reply is
+    /// numbered by `Network::send` like any other outbound message.
     pub fn reply<Payload>(&self, payload: Payload) -> Message<Payload> {
         Message {
             src: self.dest.clone(),
             dest: self.src.clone(),
             body: Body {
-                msg_id: self.body.msg_id.map(|id| id + 1),
+                msg_id: None,
                 in_reply_to: self.body.msg_id,
                 payload,
             },
@@ -69,9 +76,28 @@ impl Message<Value> {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Body<Payload> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub msg_id: Option<usize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub in_reply_to: Option<usize>,
     #[serde(flatten)]
     pub payload: Payload,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_answers_the_request_and_leaves_numbering_to_send() {
+        let mut request = Message::new("c1".to_string(), "n0".to_string(), "ping");
+        request.with_id(7);
+
+        let reply = request.reply("pong");
+        assert_eq!(reply.src, "n0");
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.msg_id, None);
+        assert_eq!(reply.body.in_reply_to, Some(7));
+        assert_eq!(reply.body.payload, "pong");
+    }
+}
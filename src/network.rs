@@ -1,73 +1,304 @@
-use crate::{Message, Payload};
+use crate::{
+    dedup::DedupCache, ErrorCode, FailureDetector, MaelstromError, Message, Payload, PeerEvent,
+    PeerStatus, Request, RpcError, Timers,
+};
+use anyhow::Context;
+use serde_json::Value;
 use std::{
     collections::HashMap,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc, Mutex,
     },
+    time::Duration,
 };
 use tokio::sync::{
-    mpsc::Sender as MpscSender,
+    broadcast,
+    mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender},
     oneshot::{self, Sender},
 };
+use tracing::{debug, trace, Instrument};
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub struct Network {
+    node_id: String,
     outbound: MpscSender<String>,
     pending: Arc<Mutex<HashMap<usize, Sender<String>>>>,
     next_id: Arc<AtomicUsize>,
+    timers: Timers,
+    dedup: Option<DedupCache>,
+    failure_detector: Option<FailureDetector>,
 }
 impl Network {
-    pub fn new(outbound: MpscSender<String>) -> Self {
+    pub fn new(node_id: String, outbound: MpscSender<String>, timers: Timers) -> Self {
         Self {
+            node_id,
             outbound,
             pending: Default::default(),
             next_id: Default::default(),
+            timers,
+            dedup: None,
+            failure_detector: None,
+        }
+    }
+
+    /// Caches the replies to the last `window` requests, see `DedupCache`.
+    pub(crate) fn with_dedup(mut self, window: usize) -> Self {
+        self.dedup = Some(DedupCache::new(window));
+        self
+    }
+
+    pub(crate) fn dedup(&self) -> Option<&DedupCache> {
+        self.dedup.as_ref()
+    }
+
+    pub(crate) fn with_failure_detector(mut self, failure_detector: FailureDetector) -> Self {
+        self.failure_detector = Some(failure_detector);
+        self
+    }
+
+    pub(crate) fn failure_detector(&self) -> Option<&FailureDetector> {
+        self.failure_detector.as_ref()
+    }
+
+    /// Whether `node` looks reachable. Always `Unknown` unless the runtime
+    /// was configured with a failure detector.
+    pub fn peer_status(&self, node: &str) -> PeerStatus {
+        self.failure_detector
+            .as_ref()
+            .map_or(PeerStatus::Unknown, |detector| detector.status(node))
+    }
+
+    /// Peer status changes from now on. Closed right away when there is no
+    /// failure detector.
+    pub fn peer_events(&self) -> broadcast::Receiver<PeerEvent> {
+        match &self.failure_detector {
+            Some(detector) => detector.subscribe(),
+            None => broadcast::channel(1).1,
         }
     }
 
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
     fn get_next_id(&self) -> usize {
         self.next_id.fetch_add(1, Ordering::SeqCst)
     }
 
-    pub async fn send_raw(&self, msg: String) {
+    /// Sends `msg` under a fresh msg_id. Every outbound message, replies
+    /// included, is numbered here, so ids never repeat within a node.
+    pub async fn send<TPayload: Payload>(&self, msg: &Message<TPayload>) {
+        if let (Some(dedup), Some(in_reply_to)) = (&self.dedup, msg.body.in_reply_to) {
+            if let Ok(payload) = serde_json::to_value(&msg.body.payload) {
+                dedup.record(&msg.dest, in_reply_to, payload);
+            }
+        }
+        let mut msg = msg.clone();
+        self.write(msg.with_id(self.get_next_id())).await
+    }
+
+    async fn write<TPayload: Payload>(&self, msg: &Message<TPayload>) {
+        let json = serde_json::to_string(msg).expect("Should be able to serialize message");
+        debug!(
+            dest = %msg.dest,
+            msg_id = msg.body.msg_id,
+            in_reply_to = msg.body.in_reply_to,
+            "sent"
+        );
+        trace!(body = %json, "sent");
         self.outbound
-            .send(msg)
+            .send(json)
             .await
             .expect("Unable to send message")
     }
 
-    pub async fn send<TPayload: Payload>(&self, msg: &Message<TPayload>) {
-        let mut msg = msg.clone();
+    pub async fn rpc<TPayload: Payload>(
+        &self,
+        msg: &mut Message<TPayload>,
+    ) -> anyhow::Result<Message<TPayload>> {
         let msg_id = self.get_next_id();
         let msg = msg.with_id(msg_id);
-        let json = serde_json::to_string(msg).expect("Should be able to serialize message");
-        self.send_raw(json).await
+
+        let (tx, rx) = oneshot::channel::<String>();
+        let _pending = self.register_pending(msg_id, tx);
+
+        self.write(msg).await;
+        let response = rx.await.context("Reply channel closed")?;
+        let r = serde_json::from_str(&response)
+            .with_context(|| format!("Should be able to deserialize message {response}"))?;
+        Ok(r)
     }
-    pub async fn send2<TPayload: Payload>(&self, msg: &Message<TPayload>) {
-        let json = serde_json::to_string(msg).expect("Should be able to serialize message");
-        self.send_raw(json).await
+
+    /// Like `rpc`, but gives up with `RpcError::Timeout` once `timeout` has
+    /// elapsed. The pending entry is removed whether the call completes,
+    /// times out or is cancelled by dropping the future.
+    pub async fn rpc_with_timeout<TPayload: Payload>(
+        &self,
+        msg: &mut Message<TPayload>,
+        timeout: Duration,
+    ) -> Result<Message<TPayload>, RpcError> {
+        let response = self.exchange(msg, timeout).await?;
+        serde_json::from_str(&response).map_err(|e| RpcError::Unknown(e.into()))
     }
 
-    pub async fn rpc<TPayload: Payload>(
+    /// Sends `request` to `dest` and waits for its typed response. Error
+    /// bodies and replies of any other type come back as a `MaelstromError`.
+    pub async fn call<TRequest: Request>(
+        &self,
+        dest: &str,
+        request: TRequest,
+    ) -> Result<TRequest::Response, MaelstromError> {
+        self.call_with_timeout(dest, request, CALL_TIMEOUT).await
+    }
+
+    pub async fn call_with_timeout<TRequest: Request>(
+        &self,
+        dest: &str,
+        request: TRequest,
+        timeout: Duration,
+    ) -> Result<TRequest::Response, MaelstromError> {
+        let mut msg = Message::new(self.node_id.clone(), dest.to_string(), request);
+        let response = self.exchange(&mut msg, timeout).await?;
+        let malformed = |e: serde_json::Error| {
+            MaelstromError::new(
+                ErrorCode::MalformedRequest,
+                format!("Unable to parse reply {response}: {e}"),
+            )
+        };
+
+        let response: Message<Value> = serde_json::from_str(&response).map_err(malformed)?;
+        let payload = response.body.payload;
+        if payload["type"] == "error" {
+            return Err(serde_json::from_value(payload).map_err(malformed)?);
+        }
+
+        let reply: TRequest::Response =
+            serde_json::from_value(payload.clone()).map_err(malformed)?;
+        // Serde does not check the tag of a tagged struct when deserializing,
+        // so compare it with the tag the expected response serializes to.
+        let expected = serde_json::to_value(&reply).map_err(malformed)?;
+        if expected["type"] != payload["type"] {
+            return Err(MaelstromError::new(
+                ErrorCode::MalformedRequest,
+                format!("Unexpected reply {payload}"),
+            ));
+        }
+        Ok(reply)
+    }
+
+    /// Calls every node in `dests` at once and streams back each node's
+    /// response as it arrives. Failed and timed out calls are streamed too,
+    /// so the stream ends after one item per destination.
+    pub fn rpc_many<TRequest: Request + Sync>(
+        &self,
+        dests: &[String],
+        request: TRequest,
+    ) -> MpscReceiver<(String, Result<TRequest::Response, MaelstromError>)> {
+        self.rpc_many_with_timeout(dests, request, CALL_TIMEOUT)
+    }
+
+    pub fn rpc_many_with_timeout<TRequest: Request + Sync>(
+        &self,
+        dests: &[String],
+        request: TRequest,
+        timeout: Duration,
+    ) -> MpscReceiver<(String, Result<TRequest::Response, MaelstromError>)> {
+        let (tx, rx) = mpsc::channel(dests.len().max(1));
+        for dest in dests {
+            let network = self.clone();
+            let request = request.clone();
+            let dest = dest.clone();
+            let tx = tx.clone();
+            tokio::spawn(
+                async move {
+                    let response = network.call_with_timeout(&dest, request, timeout).await;
+                    let _ = tx.send((dest, response)).await;
+                }
+                .in_current_span(),
+            );
+        }
+        rx
+    }
+
+    /// Calls every node in `dests` and returns as soon as `n` of them have
+    /// replied successfully, along with who they were. Fails once so many
+    /// calls have failed, or timed out after `timeout`, that `n` successes
+    /// are out of reach. Calls still running when it returns are left to
+    /// finish in the background.
+    pub async fn rpc_quorum<TRequest: Request + Sync>(
+        &self,
+        dests: &[String],
+        request: TRequest,
+        n: usize,
+        timeout: Duration,
+    ) -> Result<Vec<(String, TRequest::Response)>, MaelstromError> {
+        let mut responses = self.rpc_many_with_timeout(dests, request, timeout);
+        let mut replies = Vec::with_capacity(n);
+        let mut failures = 0;
+        let mut last_error = None;
+        while replies.len() < n {
+            let response = if dests.len() - failures < n {
+                None
+            } else {
+                responses.recv().await
+            };
+            let Some((dest, response)) = response else {
+                let code = last_error.map_or(ErrorCode::Timeout, |e: MaelstromError| e.code);
+                return Err(MaelstromError::new(
+                    code,
+                    format!(
+                        "Only {} of {n} required replies arrived, {failures} calls failed",
+                        replies.len()
+                    ),
+                ));
+            };
+            match response {
+                Ok(response) => replies.push((dest, response)),
+                Err(e) => {
+                    debug!(%dest, error = %e, "quorum call failed");
+                    failures += 1;
+                    last_error = Some(e);
+                }
+            }
+        }
+        Ok(replies)
+    }
+
+    async fn exchange<TPayload: Payload>(
         &self,
         msg: &mut Message<TPayload>,
-    ) -> anyhow::Result<Message<TPayload>> {
+        timeout: Duration,
+    ) -> Result<String, RpcError> {
         let msg_id = self.get_next_id();
         let msg = msg.with_id(msg_id);
 
         let (tx, rx) = oneshot::channel::<String>();
+        let _pending = self.register_pending(msg_id, tx);
 
+        self.write(msg).await;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(RpcError::Unknown(e.into())),
+            Err(_) => Err(RpcError::Timeout),
+        }
+    }
+
+    fn register_pending(&self, msg_id: usize, tx: Sender<String>) -> PendingGuard<'_> {
         self.pending
             .lock()
             .expect("Unable to get lock over pending map")
             .insert(msg_id, tx);
-
-        self.send2(msg).await;
-        let response = rx.await.unwrap();
-        let r = serde_json::from_str(&response)
-            .unwrap_or_else(|_| panic!("Should be able to deserialize message {response}"));
-        Ok(r)
+        PendingGuard {
+            network: self,
+            msg_id,
+        }
     }
 
     pub fn get_reply_channel(&self, msg_id: &usize) -> Option<Sender<String>> {
@@ -77,3 +308,259 @@ impl Network {
             .remove(msg_id)
     }
 }
+
+struct PendingGuard<'a> {
+    network: &'a Network,
+    msg_id: usize,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.network.get_reply_channel(&self.msg_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn network() -> (Network, MpscReceiver<String>) {
+        let (tx, rx) = mpsc::channel(16);
+        (Network::new("n0".to_string(), tx, Timers::new(0)), rx)
+    }
+
+    async fn next_sent(outbound: &mut MpscReceiver<String>) -> Message<Value> {
+        let line = outbound.recv().await.expect("A message was sent");
+        serde_json::from_str(&line).expect("Sent messages are valid")
+    }
+
+    fn reply_to(request: &Message<Value>, payload: Value) -> String {
+        let mut reply = request.reply(payload);
+        reply.with_id(100);
+        serde_json::to_string(&reply).unwrap()
+    }
+
+    fn pending(network: &Network) -> usize {
+        network.pending.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn requests_and_replies_are_numbered_by_one_allocator() {
+        let (network, mut outbound) = network();
+        let mut incoming = Message::new("n1".to_string(), "n0".to_string(), json!({}));
+        incoming.with_id(0);
+
+        let ping = Message::new(
+            "n0".to_string(),
+            "n1".to_string(),
+            json!({ "type": "ping" }),
+        );
+        network.send(&ping).await;
+        network
+            .send(&incoming.reply(json!({ "type": "pong" })))
+            .await;
+        network.send(&ping).await;
+        let caller = network.clone();
+        tokio::spawn(async move {
+            let mut msg = Message::new("n0".to_string(), "n1".to_string(), json!({}));
+            caller
+                .rpc_with_timeout(&mut msg, Duration::from_secs(1))
+                .await
+        });
+
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(next_sent(&mut outbound).await.body.msg_id);
+        }
+        assert_eq!(ids, [Some(0), Some(1), Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn replies_reach_the_call_waiting_for_them() {
+        let (network, mut outbound) = network();
+        let mut calls = Vec::new();
+        for name in ["a", "b"] {
+            let network = network.clone();
+            calls.push(tokio::spawn(async move {
+                let mut msg =
+                    Message::new("n0".to_string(), "n1".to_string(), json!({ "name": name }));
+                let reply = network
+                    .rpc_with_timeout(&mut msg, Duration::from_secs(1))
+                    .await
+                    .unwrap();
+                (name, reply)
+            }));
+        }
+        let first = next_sent(&mut outbound).await;
+        let second = next_sent(&mut outbound).await;
+
+        // Answered out of order, each reply still reaches the call with its
+        // msg_id.
+        for request in [&second, &first] {
+            let msg_id = request.body.msg_id.unwrap();
+            let reply = reply_to(request, request.body.payload.clone());
+            network
+                .get_reply_channel(&msg_id)
+                .expect("The call is waiting")
+                .send(reply)
+                .unwrap();
+        }
+        for call in calls {
+            let (name, reply) = call.await.unwrap();
+            assert_eq!(reply.body.payload["name"], name);
+        }
+        assert_eq!(pending(&network), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_calls_are_forgotten() {
+        let (network, _outbound) = network();
+        let mut msg = Message::new("n0".to_string(), "n1".to_string(), json!({}));
+
+        let result = network
+            .rpc_with_timeout(&mut msg, Duration::from_secs(1))
+            .await;
+        assert!(matches!(result, Err(RpcError::Timeout)));
+        assert_eq!(pending(&network), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_calls_are_forgotten() {
+        let (network, mut outbound) = network();
+        let caller = network.clone();
+        let call = tokio::spawn(async move {
+            let mut msg = Message::new("n0".to_string(), "n1".to_string(), json!({}));
+            caller
+                .rpc_with_timeout(&mut msg, Duration::from_secs(60))
+                .await
+        });
+        next_sent(&mut outbound).await;
+        assert_eq!(pending(&network), 1);
+
+        call.abort();
+        assert!(call.await.unwrap_err().is_cancelled());
+        assert_eq!(pending(&network), 0);
+    }
+
+    #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+    #[serde(tag = "type", rename = "ping")]
+    struct Ping {}
+
+    #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+    #[serde(tag = "type", rename = "ping_ok")]
+    struct PingOk {}
+
+    impl Request for Ping {
+        type Response = PingOk;
+    }
+
+    fn nodes(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("n{i}")).collect()
+    }
+
+    fn answer(network: &Network, request: &Message<Value>, payload: Value) {
+        network
+            .get_reply_channel(&request.body.msg_id.unwrap())
+            .expect("The call is waiting")
+            .send(reply_to(request, payload))
+            .unwrap();
+    }
+
+    fn precondition_failed() -> Value {
+        json!({ "type": "error", "code": 22, "text": "no" })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpc_many_streams_one_response_per_destination() {
+        let (network, mut outbound) = network();
+        let mut responses =
+            network.rpc_many_with_timeout(&nodes(3), Ping {}, Duration::from_secs(1));
+        let mut requests = HashMap::new();
+        for _ in 0..3 {
+            let request = next_sent(&mut outbound).await;
+            requests.insert(request.dest.clone(), request);
+        }
+        answer(&network, &requests["n1"], json!({ "type": "ping_ok" }));
+        answer(&network, &requests["n2"], precondition_failed());
+
+        let mut codes = HashMap::new();
+        while let Some((dest, response)) = responses.recv().await {
+            codes.insert(dest, response.err().map(|e| e.code));
+        }
+        assert_eq!(codes.len(), 3);
+        assert_eq!(codes["n1"], None);
+        assert_eq!(codes["n2"], Some(ErrorCode::PreconditionFailed));
+        assert_eq!(codes["n3"], Some(ErrorCode::Timeout));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpc_quorum_returns_once_n_replied() {
+        let (network, mut outbound) = network();
+        let caller = network.clone();
+        let quorum = tokio::spawn(async move {
+            caller
+                .rpc_quorum(&nodes(3), Ping {}, 2, Duration::from_secs(60))
+                .await
+        });
+        let first = next_sent(&mut outbound).await;
+        let second = next_sent(&mut outbound).await;
+        next_sent(&mut outbound).await;
+        answer(&network, &first, json!({ "type": "ping_ok" }));
+        answer(&network, &second, json!({ "type": "ping_ok" }));
+
+        let start = tokio::time::Instant::now();
+        let mut replied: Vec<_> = quorum
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|(dest, _)| dest)
+            .collect();
+        replied.sort();
+        let mut expected = vec![first.dest, second.dest];
+        expected.sort();
+        assert_eq!(replied, expected);
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpc_quorum_fails_once_n_replies_are_out_of_reach() {
+        let (network, mut outbound) = network();
+        let caller = network.clone();
+        let quorum = tokio::spawn(async move {
+            caller
+                .rpc_quorum(&nodes(3), Ping {}, 2, Duration::from_secs(60))
+                .await
+        });
+        let first = next_sent(&mut outbound).await;
+        let second = next_sent(&mut outbound).await;
+        next_sent(&mut outbound).await;
+        answer(&network, &first, precondition_failed());
+        answer(&network, &second, precondition_failed());
+
+        // The third call is still waiting, yet two of three have failed, so
+        // a quorum of two can't be reached and the call fails right away.
+        let start = tokio::time::Instant::now();
+        let error = quorum.await.unwrap().unwrap_err();
+        assert_eq!(error.code, ErrorCode::PreconditionFailed);
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rpc_quorum_times_out_without_enough_replies() {
+        let (network, mut outbound) = network();
+        let caller = network.clone();
+        let quorum = tokio::spawn(async move {
+            caller
+                .rpc_quorum(&nodes(3), Ping {}, 2, Duration::from_secs(1))
+                .await
+        });
+        let first = next_sent(&mut outbound).await;
+        answer(&network, &first, json!({ "type": "ping_ok" }));
+
+        let error = quorum.await.unwrap().unwrap_err();
+        assert_eq!(error.code, ErrorCode::Timeout);
+        assert_eq!(pending(&network), 0);
+    }
+}
@@ -10,4 +10,17 @@ where
         &self,
         message: Message<TPayload>,
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called once `init_ok` has been sent, before any other message is handled.
+    fn on_start(&self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called when the input closes or the process gets SIGTERM, after
+    /// in-flight handlers have drained (or the drain timeout has passed).
+    /// Timers and tracked tasks are cancelled once it returns, so it can
+    /// still talk to them.
+    fn on_shutdown(&self) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
 }
@@ -0,0 +1,1060 @@
+use crate::{ErrorCode, MaelstromError, Message, Network, Payload, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::{debug, info, Instrument};
+
+/// The state `Raft` replicates. Every node applies the same commands in the
+/// same order, so `apply` must be deterministic.
+pub trait StateMachine: Send + 'static {
+    type Command: Payload + Sync;
+    type Output: Payload + Sync;
+    type Snapshot: Payload + Sync;
+
+    fn apply(&mut self, command: Self::Command) -> Self::Output;
+    fn snapshot(&self) -> Self::Snapshot;
+    /// Replaces the whole state with `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// How often the leader sends entries, or empty heartbeats.
+    pub heartbeat_interval: Duration,
+    /// A follower that hears from no leader for a random time between these
+    /// starts an election.
+    pub min_election_timeout: Duration,
+    pub max_election_timeout: Duration,
+    pub rpc_timeout: Duration,
+    /// How long `propose` waits for its command to be applied.
+    pub propose_timeout: Duration,
+    /// Most entries sent in one append.
+    pub max_batch: usize,
+    /// Applied entries kept in the log before it is compacted into a
+    /// snapshot.
+    pub snapshot_threshold: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_millis(50),
+            min_election_timeout: Duration::from_millis(300),
+            max_election_timeout: Duration::from_millis(600),
+            rpc_timeout: Duration::from_millis(200),
+            propose_timeout: Duration::from_secs(2),
+            max_batch: 128,
+            snapshot_threshold: 1024,
+        }
+    }
+}
+
+/// A log entry. New leaders append one without a command, since they may
+/// only commit entries of earlier terms along with one of their own.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Entry<TCommand> {
+    term: u64,
+    command: Option<TCommand>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_vote")]
+struct RequestVote {
+    term: u64,
+    candidate: String,
+    last_log_index: usize,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_vote_ok")]
+struct RequestVoteOk {
+    term: u64,
+    granted: bool,
+}
+
+impl Request for RequestVote {
+    type Response = RequestVoteOk;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_append")]
+struct AppendEntries<TCommand> {
+    term: u64,
+    leader: String,
+    prev_log_index: usize,
+    prev_log_term: u64,
+    entries: Vec<Entry<TCommand>>,
+    leader_commit: usize,
+}
+
+/// On success `index` is the last entry known to match the leader's log;
+/// on failure it is where the leader should retry from.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_append_ok")]
+struct AppendEntriesOk {
+    term: u64,
+    success: bool,
+    index: usize,
+}
+
+impl<TCommand: Payload + Sync> Request for AppendEntries<TCommand> {
+    type Response = AppendEntriesOk;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_snapshot")]
+struct InstallSnapshot<TSnapshot> {
+    term: u64,
+    leader: String,
+    last_index: usize,
+    last_term: u64,
+    snapshot: TSnapshot,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_snapshot_ok")]
+struct InstallSnapshotOk {
+    term: u64,
+}
+
+impl<TSnapshot: Payload + Sync> Request for InstallSnapshot<TSnapshot> {
+    type Response = InstallSnapshotOk;
+}
+
+/// A command forwarded to the leader by a follower.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_propose")]
+struct Propose<TCommand, TOutput> {
+    command: TCommand,
+    #[serde(skip)]
+    _phantom: PhantomData<TOutput>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "raft_propose_ok")]
+struct ProposeOk<TOutput> {
+    output: TOutput,
+}
+
+impl<TCommand: Payload + Sync, TOutput: Payload + Sync> Request for Propose<TCommand, TOutput> {
+    type Response = ProposeOk<TOutput>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct State<TMachine: StateMachine> {
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    /// The entries after the snapshot; the first one is at
+    /// `snapshot_index + 1`. Indexes start at 1.
+    log: Vec<Entry<TMachine::Command>>,
+    snapshot_index: usize,
+    snapshot_term: u64,
+    snapshot: Option<TMachine::Snapshot>,
+    commit_index: usize,
+    last_applied: usize,
+    machine: TMachine,
+    election_deadline: Instant,
+    votes: HashSet<String>,
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    /// Peers with an append or snapshot on the way, so a slow peer gets one
+    /// request at a time.
+    in_flight: HashSet<String>,
+    /// The callers of `propose` waiting for the entry at each index, with
+    /// the term it was appended in.
+    waiters: HashMap<usize, (u64, Waiter<TMachine::Output>)>,
+}
+
+type Waiter<TOutput> = oneshot::Sender<Result<TOutput, MaelstromError>>;
+
+impl<TMachine: StateMachine> State<TMachine> {
+    fn last_index(&self) -> usize {
+        self.snapshot_index + self.log.len()
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, unless it is compacted away or does
+    /// not exist yet.
+    fn term_at(&self, index: usize) -> Option<u64> {
+        match index.checked_sub(self.snapshot_index) {
+            Some(0) => Some(self.snapshot_term),
+            Some(offset) => self.log.get(offset - 1).map(|entry| entry.term),
+            None => None,
+        }
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        if self.role != Role::Follower {
+            debug!(term, "became follower");
+        }
+        self.role = Role::Follower;
+        self.votes.clear();
+        self.in_flight.clear();
+    }
+}
+
+/// A Raft replicated state machine over `Network`: leader election, log
+/// replication, commitment and snapshotting.
+///
+/// Any node can `propose` a command; followers forward it to the leader.
+/// State lives in memory only, as Maelstrom never restarts a node. Nodes
+/// route `raft_vote`, `raft_append`, `raft_snapshot` and `raft_propose`
+/// messages to `receive`.
+pub struct Raft<TMachine: StateMachine> {
+    network: Network,
+    config: RaftConfig,
+    peers: Vec<String>,
+    state: Arc<Mutex<State<TMachine>>>,
+}
+
+impl<TMachine: StateMachine> Clone for Raft<TMachine> {
+    fn clone(&self) -> Self {
+        Self {
+            network: self.network.clone(),
+            config: self.config.clone(),
+            peers: self.peers.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<TMachine: StateMachine> Raft<TMachine> {
+    /// Starts a member of the cluster of `node_ids`, which may include this
+    /// node, until the timers are cancelled.
+    pub fn start(
+        network: &Network,
+        node_ids: &[String],
+        machine: TMachine,
+        config: RaftConfig,
+    ) -> Self {
+        let peers = node_ids
+            .iter()
+            .filter(|id| *id != network.node_id())
+            .cloned()
+            .collect();
+        let state = State {
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: None,
+            commit_index: 0,
+            last_applied: 0,
+            machine,
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            in_flight: HashSet::new(),
+            waiters: HashMap::new(),
+        };
+        let raft = Self {
+            network: network.clone(),
+            config,
+            peers,
+            state: Arc::new(Mutex::new(state)),
+        };
+        raft.reset_election_deadline(&mut raft.lock());
+
+        let this = raft.clone();
+        network
+            .timers()
+            .every(raft.config.heartbeat_interval, Duration::ZERO, move || {
+                let this = this.clone();
+                async move { this.tick() }
+            });
+        raft
+    }
+
+    /// The leader as far as this node knows.
+    pub fn leader(&self) -> Option<String> {
+        self.lock().leader.clone()
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock().role == Role::Leader
+    }
+
+    /// Replicates `command` and returns its output once it is committed and
+    /// applied. Fails with a definite error if the command was dropped from
+    /// the log, and with a timeout if its fate is unknown.
+    pub async fn propose(
+        &self,
+        command: TMachine::Command,
+    ) -> Result<TMachine::Output, MaelstromError> {
+        let leader = {
+            let state = self.lock();
+            state
+                .leader
+                .clone()
+                .filter(|_| state.role != Role::Leader)
+                .filter(|leader| leader != self.network.node_id())
+        };
+        let Some(leader) = leader else {
+            return self.propose_here(command).await;
+        };
+        let request = Propose {
+            command,
+            _phantom: PhantomData,
+        };
+        let ProposeOk { output } = self
+            .network
+            .call_with_timeout(&leader, request, self.config.propose_timeout)
+            .await?;
+        Ok(output)
+    }
+
+    pub async fn receive(&self, msg: Message<Value>) -> anyhow::Result<()> {
+        match msg.get_payload()["type"].as_str() {
+            Some("raft_vote") => {
+                let msg = msg.into_typed::<RequestVote>()?;
+                let reply = self.on_request_vote(&msg.body.payload);
+                self.network.send(&msg.reply(reply)).await;
+            }
+            Some("raft_append") => {
+                let msg = msg.into_typed::<AppendEntries<TMachine::Command>>()?;
+                let reply = self.on_append_entries(msg.body.payload.clone());
+                self.network.send(&msg.reply(reply)).await;
+            }
+            Some("raft_snapshot") => {
+                let msg = msg.into_typed::<InstallSnapshot<TMachine::Snapshot>>()?;
+                let reply = self.on_install_snapshot(msg.body.payload.clone());
+                self.network.send(&msg.reply(reply)).await;
+            }
+            Some("raft_propose") => {
+                let msg = msg.into_typed::<Propose<TMachine::Command, TMachine::Output>>()?;
+                // Forwarded commands are not forwarded again, so they cannot
+                // loop while leadership moves.
+                let output = self.propose_here(msg.body.payload.command.clone()).await?;
+                self.network.send(&msg.reply(ProposeOk { output })).await;
+            }
+            other => anyhow::bail!("Unexpected raft message {other:?}"),
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<TMachine>> {
+        self.state.lock().expect("Unable to lock raft state")
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn reset_election_deadline(&self, state: &mut State<TMachine>) {
+        let spread = self
+            .config
+            .max_election_timeout
+            .saturating_sub(self.config.min_election_timeout);
+        state.election_deadline = Instant::now()
+            + self.config.min_election_timeout
+            + self.network.timers().random_delay(spread);
+    }
+
+    fn tick(&self) {
+        let state = self.lock();
+        match state.role {
+            Role::Leader => {
+                drop(state);
+                self.replicate_all();
+            }
+            _ if Instant::now() >= state.election_deadline => self.start_election(state),
+            _ => {}
+        }
+    }
+
+    fn start_election(&self, mut state: MutexGuard<'_, State<TMachine>>) {
+        let node_id = self.network.node_id().to_string();
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.voted_for = Some(node_id.clone());
+        state.leader = None;
+        state.votes = HashSet::from([node_id.clone()]);
+        self.reset_election_deadline(&mut state);
+        info!(term = state.term, "starting election");
+
+        if state.votes.len() >= self.quorum() {
+            self.become_leader(&mut state);
+            drop(state);
+            self.replicate_all();
+            return;
+        }
+
+        let request = RequestVote {
+            term: state.term,
+            candidate: node_id,
+            last_log_index: state.last_index(),
+            last_log_term: state.last_term(),
+        };
+        drop(state);
+        for peer in &self.peers {
+            let this = self.clone();
+            let peer = peer.clone();
+            let request = request.clone();
+            let task = tokio::spawn(
+                async move {
+                    let term = request.term;
+                    let result = this
+                        .network
+                        .call_with_timeout(&peer, request, this.config.rpc_timeout)
+                        .await;
+                    if let Ok(reply) = result {
+                        this.on_vote(peer, term, reply);
+                    }
+                }
+                .in_current_span(),
+            );
+            self.network.timers().track(task.abort_handle());
+        }
+    }
+
+    fn on_vote(&self, peer: String, term: u64, reply: RequestVoteOk) {
+        let mut state = self.lock();
+        if reply.term > state.term {
+            state.become_follower(reply.term);
+            return;
+        }
+        if state.role != Role::Candidate || state.term != term || !reply.granted {
+            return;
+        }
+        state.votes.insert(peer);
+        if state.votes.len() >= self.quorum() {
+            self.become_leader(&mut state);
+            drop(state);
+            self.replicate_all();
+        }
+    }
+
+    fn become_leader(&self, state: &mut State<TMachine>) {
+        info!(term = state.term, "became leader");
+        state.role = Role::Leader;
+        state.leader = Some(self.network.node_id().to_string());
+        let next = state.last_index() + 1;
+        state.next_index = self.peers.iter().map(|peer| (peer.clone(), next)).collect();
+        state.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        state.in_flight.clear();
+        let term = state.term;
+        state.log.push(Entry {
+            term,
+            command: None,
+        });
+        self.advance_commit(state);
+    }
+
+    async fn propose_here(
+        &self,
+        command: TMachine::Command,
+    ) -> Result<TMachine::Output, MaelstromError> {
+        let waiter = {
+            let mut state = self.lock();
+            if state.role != Role::Leader {
+                return Err(MaelstromError::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    "there is no leader to propose to",
+                ));
+            }
+            let term = state.term;
+            state.log.push(Entry {
+                term,
+                command: Some(command),
+            });
+            let (tx, rx) = oneshot::channel();
+            let index = state.last_index();
+            state.waiters.insert(index, (term, tx));
+            self.advance_commit(&mut state);
+            rx
+        };
+        self.replicate_all();
+
+        match tokio::time::timeout(self.config.propose_timeout, waiter).await {
+            Ok(Ok(result)) => result,
+            _ => Err(MaelstromError::new(
+                ErrorCode::Timeout,
+                "command was not applied in time",
+            )),
+        }
+    }
+
+    fn replicate_all(&self) {
+        for peer in &self.peers {
+            self.replicate(peer);
+        }
+    }
+
+    /// Sends `peer` the entries it is missing, or the snapshot if they were
+    /// compacted away.
+    fn replicate(&self, peer: &str) {
+        let mut state = self.lock();
+        if state.role != Role::Leader || !state.in_flight.insert(peer.to_string()) {
+            return;
+        }
+        let term = state.term;
+        let leader = self.network.node_id().to_string();
+        let next = state.next_index.get(peer).copied().unwrap_or(1);
+        let this = self.clone();
+        let peer = peer.to_string();
+
+        if next <= state.snapshot_index {
+            let request = InstallSnapshot {
+                term,
+                leader,
+                last_index: state.snapshot_index,
+                last_term: state.snapshot_term,
+                snapshot: state
+                    .snapshot
+                    .clone()
+                    .expect("Compacted log has a snapshot"),
+            };
+            drop(state);
+            let task = tokio::spawn(
+                async move {
+                    let last_index = request.last_index;
+                    let result = this
+                        .network
+                        .call_with_timeout(&peer, request, this.config.rpc_timeout)
+                        .await;
+                    let result = result.map(|reply| AppendEntriesOk {
+                        term: reply.term,
+                        success: true,
+                        index: last_index,
+                    });
+                    this.on_append_reply(peer, term, result.ok());
+                }
+                .in_current_span(),
+            );
+            self.network.timers().track(task.abort_handle());
+            return;
+        }
+
+        let prev_log_index = next - 1;
+        let request = AppendEntries {
+            term,
+            leader,
+            prev_log_index,
+            prev_log_term: state.term_at(prev_log_index).unwrap_or_default(),
+            entries: state.log[prev_log_index - state.snapshot_index..]
+                .iter()
+                .take(self.config.max_batch)
+                .cloned()
+                .collect(),
+            leader_commit: state.commit_index,
+        };
+        drop(state);
+        let task = tokio::spawn(
+            async move {
+                let result = this
+                    .network
+                    .call_with_timeout(&peer, request, this.config.rpc_timeout)
+                    .await;
+                this.on_append_reply(peer, term, result.ok());
+            }
+            .in_current_span(),
+        );
+        self.network.timers().track(task.abort_handle());
+    }
+
+    fn on_append_reply(&self, peer: String, term: u64, reply: Option<AppendEntriesOk>) {
+        let mut state = self.lock();
+        state.in_flight.remove(&peer);
+        let Some(reply) = reply else {
+            return;
+        };
+        if reply.term > state.term {
+            state.become_follower(reply.term);
+            return;
+        }
+        if state.role != Role::Leader || state.term != term {
+            return;
+        }
+
+        let next = state.next_index.get(&peer).copied().unwrap_or(1);
+        if reply.success {
+            let matched = state.match_index.entry(peer.clone()).or_default();
+            *matched = (*matched).max(reply.index);
+            let matched = *matched;
+            state.next_index.insert(peer.clone(), matched + 1);
+            self.advance_commit(&mut state);
+        } else {
+            // Always back off, even if the hint would not.
+            let retry = reply.index.min(next - 1).max(1);
+            state.next_index.insert(peer.clone(), retry);
+        }
+        let behind = state.next_index.get(&peer).copied().unwrap_or(1) <= state.last_index();
+        drop(state);
+        if behind {
+            self.replicate(&peer);
+        }
+    }
+
+    /// Commits the latest entry of the current term stored on a quorum.
+    fn advance_commit(&self, state: &mut State<TMachine>) {
+        if state.role != Role::Leader {
+            return;
+        }
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            if state.term_at(index) != Some(state.term) {
+                break;
+            }
+            let replicas = 1 + state.match_index.values().filter(|m| **m >= index).count();
+            if replicas >= self.quorum() {
+                state.commit_index = index;
+                self.apply_committed(state);
+                break;
+            }
+        }
+    }
+
+    fn apply_committed(&self, state: &mut State<TMachine>) {
+        while state.last_applied < state.commit_index {
+            state.last_applied += 1;
+            let index = state.last_applied;
+            let entry = state.log[index - state.snapshot_index - 1].clone();
+            let output = entry.command.map(|command| state.machine.apply(command));
+            if let Some((term, waiter)) = state.waiters.remove(&index) {
+                let result = match output {
+                    Some(output) if term == entry.term => Ok(output),
+                    _ => Err(MaelstromError::new(
+                        ErrorCode::TemporarilyUnavailable,
+                        "command was dropped by a new leader",
+                    )),
+                };
+                let _ = waiter.send(result);
+            }
+        }
+
+        if state.last_applied - state.snapshot_index >= self.config.snapshot_threshold {
+            let index = state.last_applied;
+            let term = state.term_at(index).expect("Applied entry is in the log");
+            state.snapshot = Some(state.machine.snapshot());
+            let compacted = index - state.snapshot_index;
+            state.log.drain(..compacted);
+            state.snapshot_index = index;
+            state.snapshot_term = term;
+            debug!(index, "compacted log into a snapshot");
+        }
+    }
+
+    fn on_request_vote(&self, request: &RequestVote) -> RequestVoteOk {
+        let mut state = self.lock();
+        if request.term > state.term {
+            state.become_follower(request.term);
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index)
+            >= (state.last_term(), state.last_index());
+        let granted = request.term == state.term
+            && up_to_date
+            && state
+                .voted_for
+                .as_ref()
+                .is_none_or(|candidate| *candidate == request.candidate);
+        if granted {
+            state.voted_for = Some(request.candidate.clone());
+            self.reset_election_deadline(&mut state);
+        }
+        RequestVoteOk {
+            term: state.term,
+            granted,
+        }
+    }
+
+    fn on_append_entries(&self, request: AppendEntries<TMachine::Command>) -> AppendEntriesOk {
+        let mut state = self.lock();
+        if request.term < state.term {
+            return AppendEntriesOk {
+                term: state.term,
+                success: false,
+                index: 0,
+            };
+        }
+        if request.term > state.term || state.role != Role::Follower {
+            state.become_follower(request.term);
+        }
+        state.leader = Some(request.leader);
+        self.reset_election_deadline(&mut state);
+
+        let failure = |state: &State<TMachine>, index| AppendEntriesOk {
+            term: state.term,
+            success: false,
+            index,
+        };
+        if request.prev_log_index > state.last_index() {
+            return failure(&state, state.last_index() + 1);
+        }
+
+        let mut prev = request.prev_log_index;
+        let mut entries = request.entries;
+        if prev < state.snapshot_index {
+            // Entries up to the snapshot are committed, so they match.
+            let covered = (state.snapshot_index - prev).min(entries.len());
+            entries.drain(..covered);
+            prev += covered;
+        } else if state.term_at(prev) != Some(request.prev_log_term) {
+            // Skip back over the whole conflicting term in one round trip.
+            let conflicting = state.term_at(prev);
+            let mut index = prev;
+            while index > state.snapshot_index + 1 && state.term_at(index - 1) == conflicting {
+                index -= 1;
+            }
+            return failure(&state, index);
+        }
+
+        let last_new = prev + entries.len();
+        for (offset, entry) in entries.into_iter().enumerate() {
+            let index = prev + 1 + offset;
+            match state.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    let keep = index - state.snapshot_index - 1;
+                    state.log.truncate(keep);
+                    state.log.push(entry);
+                }
+                None => state.log.push(entry),
+            }
+        }
+
+        if request.leader_commit > state.commit_index {
+            state.commit_index = request.leader_commit.min(last_new).max(state.commit_index);
+            self.apply_committed(&mut state);
+        }
+        AppendEntriesOk {
+            term: state.term,
+            success: true,
+            index: last_new,
+        }
+    }
+
+    fn on_install_snapshot(
+        &self,
+        request: InstallSnapshot<TMachine::Snapshot>,
+    ) -> InstallSnapshotOk {
+        let mut state = self.lock();
+        if request.term < state.term {
+            return InstallSnapshotOk { term: state.term };
+        }
+        if request.term > state.term || state.role != Role::Follower {
+            state.become_follower(request.term);
+        }
+        state.leader = Some(request.leader);
+        self.reset_election_deadline(&mut state);
+        if request.last_index <= state.commit_index {
+            return InstallSnapshotOk { term: state.term };
+        }
+
+        if state.term_at(request.last_index) == Some(request.last_term) {
+            let compacted = request.last_index - state.snapshot_index;
+            state.log.drain(..compacted);
+        } else {
+            state.log.clear();
+        }
+        state.machine.restore(request.snapshot.clone());
+        state.snapshot = Some(request.snapshot);
+        state.snapshot_index = request.last_index;
+        state.snapshot_term = request.last_term;
+        state.commit_index = request.last_index;
+        state.last_applied = request.last_index;
+        // Whether these commands made it into the snapshot is unknown.
+        let last_index = request.last_index;
+        let waiting: Vec<usize> = state
+            .waiters
+            .keys()
+            .filter(|index| **index <= last_index)
+            .copied()
+            .collect();
+        for index in waiting {
+            if let Some((_, waiter)) = state.waiters.remove(&index) {
+                let _ = waiter.send(Err(MaelstromError::new(
+                    ErrorCode::Timeout,
+                    "command was replaced by a snapshot",
+                )));
+            }
+        }
+        debug!(index = last_index, "installed snapshot");
+        InstallSnapshotOk { term: state.term }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timers;
+    use tokio::sync::mpsc;
+
+    /// Records the commands applied to it.
+    #[derive(Default)]
+    struct Recorder {
+        applied: Vec<u64>,
+    }
+
+    impl StateMachine for Recorder {
+        type Command = u64;
+        type Output = usize;
+        type Snapshot = Vec<u64>;
+
+        fn apply(&mut self, command: u64) -> usize {
+            self.applied.push(command);
+            self.applied.len()
+        }
+
+        fn snapshot(&self) -> Vec<u64> {
+            self.applied.clone()
+        }
+
+        fn restore(&mut self, snapshot: Vec<u64>) {
+            self.applied = snapshot;
+        }
+    }
+
+    /// `n0` of a three node cluster. Its sends pile up unread, and with the
+    /// clock paused its timers only fire if a test sleeps.
+    fn raft(snapshot_threshold: usize) -> (Raft<Recorder>, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel(1024);
+        let network = Network::new("n0".to_string(), tx, Timers::new(0));
+        let node_ids = ["n0", "n1", "n2"].map(String::from);
+        let config = RaftConfig {
+            snapshot_threshold,
+            ..RaftConfig::default()
+        };
+        (
+            Raft::start(&network, &node_ids, Recorder::default(), config),
+            rx,
+        )
+    }
+
+    fn entry(term: u64, command: u64) -> Entry<u64> {
+        Entry {
+            term,
+            command: Some(command),
+        }
+    }
+
+    fn append(
+        term: u64,
+        prev_log_index: usize,
+        prev_log_term: u64,
+        entries: Vec<Entry<u64>>,
+        leader_commit: usize,
+    ) -> AppendEntries<u64> {
+        AppendEntries {
+            term,
+            leader: "n1".to_string(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        }
+    }
+
+    fn acked(term: u64, index: usize) -> Option<AppendEntriesOk> {
+        Some(AppendEntriesOk {
+            term,
+            success: true,
+            index,
+        })
+    }
+
+    fn log_of(raft: &Raft<Recorder>) -> Vec<(u64, Option<u64>)> {
+        raft.lock()
+            .log
+            .iter()
+            .map(|entry| (entry.term, entry.command))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn conflicting_entries_are_truncated() {
+        let (raft, _outbound) = raft(1024);
+        let entries = vec![entry(1, 1), entry(1, 2), entry(1, 3)];
+        let reply = raft.on_append_entries(append(1, 0, 0, entries, 0));
+        assert!(reply.success);
+        assert_eq!(reply.index, 3);
+
+        // A leader of term 2 has a different entry at index 2.
+        let reply = raft.on_append_entries(append(2, 1, 1, vec![entry(2, 4)], 0));
+        assert!(reply.success);
+        assert_eq!(reply.index, 2);
+        assert_eq!(log_of(&raft), [(1, Some(1)), (2, Some(4))]);
+
+        // A repeated, older append does not cut off what followed it.
+        let reply = raft.on_append_entries(append(2, 0, 0, vec![entry(1, 1)], 0));
+        assert!(reply.success);
+        assert_eq!(log_of(&raft), [(1, Some(1)), (2, Some(4))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mismatched_appends_skip_back_over_the_conflicting_term() {
+        let (raft, _outbound) = raft(1024);
+        let entries = vec![entry(1, 1), entry(2, 2), entry(2, 3), entry(2, 4)];
+        raft.on_append_entries(append(2, 0, 0, entries, 0));
+
+        let reply = raft.on_append_entries(append(3, 4, 3, Vec::new(), 0));
+        assert!(!reply.success);
+        assert_eq!(reply.index, 2);
+        let reply = raft.on_append_entries(append(3, 9, 3, Vec::new(), 0));
+        assert!(!reply.success);
+        assert_eq!(reply.index, 5);
+        assert_eq!(raft.lock().log.len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaders_commit_only_entries_of_their_term_on_a_quorum() {
+        let (raft, _outbound) = raft(1024);
+        raft.on_append_entries(append(1, 0, 0, vec![entry(1, 1)], 0));
+        {
+            let mut state = raft.lock();
+            state.term = 2;
+            raft.become_leader(&mut state);
+        }
+        assert_eq!(log_of(&raft), [(1, Some(1)), (2, None)]);
+
+        // A quorum holds the entry of term 1, which is not enough alone.
+        raft.on_append_reply("n1".to_string(), 2, acked(2, 1));
+        assert_eq!(raft.lock().commit_index, 0);
+
+        raft.on_append_reply("n1".to_string(), 2, acked(2, 2));
+        let state = raft.lock();
+        assert_eq!(state.commit_index, 2);
+        assert_eq!(state.last_applied, 2);
+        assert_eq!(state.machine.applied, [1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn followers_commit_what_the_leader_committed() {
+        let (raft, _outbound) = raft(1024);
+        let entries = vec![entry(1, 1), entry(1, 2), entry(1, 3)];
+        raft.on_append_entries(append(1, 0, 0, entries, 2));
+        let state = raft.lock();
+        assert_eq!(state.commit_index, 2);
+        assert_eq!(state.machine.applied, [1, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn higher_terms_make_leaders_step_down() {
+        let (raft, _outbound) = raft(1024);
+        {
+            let mut state = raft.lock();
+            state.term = 2;
+            raft.become_leader(&mut state);
+        }
+        assert!(raft.is_leader());
+
+        let reply = raft.on_request_vote(&RequestVote {
+            term: 3,
+            candidate: "n1".to_string(),
+            last_log_index: 1,
+            last_log_term: 2,
+        });
+        assert!(reply.granted);
+        assert!(!raft.is_leader());
+        {
+            let state = raft.lock();
+            assert_eq!(state.term, 3);
+            assert_eq!(state.voted_for.as_deref(), Some("n1"));
+            assert_eq!(state.leader, None);
+        }
+
+        // One vote per term, and none for older terms.
+        let vote = |term, candidate: &str| {
+            raft.on_request_vote(&RequestVote {
+                term,
+                candidate: candidate.to_string(),
+                last_log_index: 1,
+                last_log_term: 2,
+            })
+        };
+        assert!(!vote(3, "n2").granted);
+        assert!(!vote(2, "n2").granted);
+        let reply = raft.on_append_entries(append(2, 0, 0, Vec::new(), 0));
+        assert!(!reply.success);
+        assert_eq!(reply.term, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaders_step_down_on_a_reply_from_a_higher_term() {
+        let (raft, _outbound) = raft(1024);
+        {
+            let mut state = raft.lock();
+            state.term = 2;
+            raft.become_leader(&mut state);
+        }
+        raft.on_append_reply("n1".to_string(), 2, acked(4, 0));
+        assert!(!raft.is_leader());
+        assert_eq!(raft.lock().term, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn applied_entries_are_compacted_into_a_snapshot() {
+        let (raft, _outbound) = raft(2);
+        let entries = vec![entry(1, 1), entry(1, 2), entry(1, 3)];
+        raft.on_append_entries(append(1, 0, 0, entries, 3));
+        let state = raft.lock();
+        assert_eq!(state.snapshot_index, 3);
+        assert_eq!(state.snapshot_term, 1);
+        assert_eq!(state.snapshot, Some(vec![1, 2, 3]));
+        assert!(state.log.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn installed_snapshots_replace_the_state() {
+        let (raft, _outbound) = raft(1024);
+        raft.on_append_entries(append(1, 0, 0, vec![entry(1, 1), entry(1, 9)], 1));
+
+        let reply = raft.on_install_snapshot(InstallSnapshot {
+            term: 2,
+            leader: "n1".to_string(),
+            last_index: 5,
+            last_term: 2,
+            snapshot: vec![1, 2, 3, 4, 5],
+        });
+        assert_eq!(reply.term, 2);
+        {
+            let state = raft.lock();
+            assert_eq!(state.machine.applied, [1, 2, 3, 4, 5]);
+            assert_eq!((state.commit_index, state.last_applied), (5, 5));
+            assert!(state.log.is_empty());
+        }
+
+        // The log continues after the snapshot.
+        let reply = raft.on_append_entries(append(2, 5, 2, vec![entry(2, 6)], 6));
+        assert!(reply.success);
+        assert_eq!(raft.lock().machine.applied, [1, 2, 3, 4, 5, 6]);
+
+        // A stale snapshot changes nothing.
+        raft.on_install_snapshot(InstallSnapshot {
+            term: 2,
+            leader: "n1".to_string(),
+            last_index: 3,
+            last_term: 2,
+            snapshot: vec![1, 2, 3],
+        });
+        assert_eq!(raft.lock().machine.applied, [1, 2, 3, 4, 5, 6]);
+    }
+}
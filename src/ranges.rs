@@ -0,0 +1,210 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// The most values a set read from the wire may hold. Far more than any
+/// workload broadcasts, and little enough that reading or digesting the set
+/// stays quick.
+const MAX_DECODED_LEN: usize = 1 << 20;
+
+/// A set of integers stored as disjoint inclusive ranges, so dense sets like
+/// the values of a broadcast workload stay small. Serializes to a list of
+/// `[start, end]` pairs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSet {
+    /// Start to end of each range. Ranges neither overlap nor touch.
+    ranges: BTreeMap<usize, usize>,
+    len: usize,
+}
+
+impl RangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `value` was not in the set yet.
+    pub fn insert(&mut self, value: usize) -> bool {
+        self.insert_range(value, value) > 0
+    }
+
+    /// Inserts `start..=end` and returns how many of its values are new.
+    /// Panics if the set would then hold every `usize`, which it can't count.
+    pub fn insert_range(&mut self, start: usize, end: usize) -> usize {
+        if start > end {
+            return 0;
+        }
+        let before = self.len;
+        let (mut merged_start, mut merged_end) = (start, end);
+        let touching: Vec<(usize, usize)> = self
+            .ranges
+            .range(..=end.saturating_add(1))
+            .rev()
+            .take_while(|(_, e)| e.saturating_add(1) >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in touching {
+            self.ranges.remove(&s);
+            self.len -= e - s + 1;
+            merged_start = merged_start.min(s);
+            merged_end = merged_end.max(e);
+        }
+        self.ranges.insert(merged_start, merged_end);
+        self.len = (merged_end - merged_start)
+            .checked_add(1)
+            .and_then(|merged| self.len.checked_add(merged))
+            .expect("RangeSet can't hold every usize");
+        self.len - before
+    }
+
+    /// Inserts every value of `other` and returns how many were new.
+    pub fn extend(&mut self, other: &RangeSet) -> usize {
+        other
+            .ranges()
+            .map(|(start, end)| self.insert_range(start, end))
+            .sum()
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges.iter().map(|(start, end)| (*start, *end))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges().flat_map(|(start, end)| start..=end)
+    }
+
+    /// The values of the set within `start..=end`.
+    pub fn slice(&self, start: usize, end: usize) -> RangeSet {
+        let mut result = RangeSet::new();
+        if start > end {
+            return result;
+        }
+        let first = self
+            .ranges
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(s, _)| *s);
+        for (s, e) in self.ranges.range(first..=end) {
+            result.insert_range((*s).max(start), (*e).min(end));
+        }
+        result
+    }
+
+    /// The values in both `self` and `other`.
+    pub fn intersection(&self, other: &RangeSet) -> RangeSet {
+        self.difference(&self.difference(other))
+    }
+
+    /// The values in `self` that are not in `other`.
+    pub fn difference(&self, other: &RangeSet) -> RangeSet {
+        let mut result = RangeSet::new();
+        for (start, end) in self.ranges() {
+            let mut cursor = Some(start);
+            let first = other
+                .ranges
+                .range(..=start)
+                .next_back()
+                .map_or(start, |(s, _)| *s);
+            for (s, e) in other.ranges.range(first..=end) {
+                let Some(from) = cursor else {
+                    break;
+                };
+                if *e < from {
+                    continue;
+                }
+                if *s > from {
+                    result.insert_range(from, s - 1);
+                }
+                cursor = e.checked_add(1).filter(|next| *next <= end);
+            }
+            if let Some(from) = cursor {
+                result.insert_range(from, end);
+            }
+        }
+        result
+    }
+}
+
+impl FromIterator<usize> for RangeSet {
+    fn from_iter<T: IntoIterator<Item = usize>>(iter: T) -> Self {
+        let mut set = RangeSet::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+impl Serialize for RangeSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ranges().map(|(start, end)| [start, end]))
+    }
+}
+
+impl<'de> Deserialize<'de> for RangeSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ranges: Vec<(usize, usize)> = Deserialize::deserialize(deserializer)?;
+        let mut set = RangeSet::new();
+        for (start, end) in ranges {
+            if start > end {
+                return Err(de::Error::custom(format!(
+                    "range [{start}, {end}] ends before it starts"
+                )));
+            }
+            // Counted before inserting, so a range of every usize can't
+            // overflow the length.
+            let len = end - start;
+            if len >= MAX_DECODED_LEN || set.len() + len >= MAX_DECODED_LEN {
+                return Err(de::Error::custom(format!(
+                    "more than {MAX_DECODED_LEN} values"
+                )));
+            }
+            set.insert_range(start, end);
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_read_from_the_wire_are_bounded() {
+        let set: RangeSet = serde_json::from_str("[[0, 2], [5, 5]]").unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0, 1, 2, 5]);
+
+        let every_usize = format!("[[0, {}]]", usize::MAX);
+        assert!(serde_json::from_str::<RangeSet>(&every_usize).is_err());
+        let too_many = format!(
+            "[[0, {}], [{}, {}]]",
+            MAX_DECODED_LEN / 2,
+            MAX_DECODED_LEN,
+            MAX_DECODED_LEN * 2
+        );
+        assert!(serde_json::from_str::<RangeSet>(&too_many).is_err());
+        assert!(serde_json::from_str::<RangeSet>("[[3, 1]]").is_err());
+    }
+
+    #[test]
+    fn ranges_up_to_the_last_usize_are_counted() {
+        let mut set = RangeSet::new();
+        assert_eq!(set.insert_range(usize::MAX - 1, usize::MAX), 2);
+        assert_eq!(set.insert_range(1, 2), 2);
+        assert_eq!(set.len(), 4);
+        assert!(set.contains(usize::MAX));
+    }
+}
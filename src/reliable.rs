@@ -0,0 +1,312 @@
+use crate::{Message, Network, Payload, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+use tracing::{debug, Instrument};
+
+/// A batch of messages from one peer's outbox, oldest first. Each carries
+/// its sequence number on that link.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "reliable")]
+pub struct Deliver {
+    /// The sender's incarnation. A restarted sender numbers its messages
+    /// from 1 again under a later epoch.
+    pub epoch: u64,
+    pub messages: Vec<(usize, Value)>,
+}
+
+/// Acknowledges every message of the link up to and including `up_to`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "reliable_ok")]
+pub struct DeliverOk {
+    pub up_to: usize,
+}
+
+impl Request for Deliver {
+    type Response = DeliverOk;
+}
+
+#[derive(Clone, Debug)]
+pub struct ReliableConfig {
+    /// How long to wait for an ack before retransmitting.
+    pub ack_timeout: Duration,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Most messages sent in one batch.
+    pub max_batch: usize,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_millis(500),
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            max_batch: 64,
+        }
+    }
+}
+
+/// At-least-once delivery between nodes on top of `Network`.
+///
+/// Messages queue per peer until that peer acks them, and are retransmitted
+/// with exponential backoff in the meantime, so they survive partitions.
+/// Links are ordered, which lets the receiver drop duplicates by remembering
+/// only the highest sequence number it delivered per sender and epoch. Nodes
+/// route `reliable` messages to `receive`.
+#[derive(Clone, Debug)]
+pub struct Reliable {
+    network: Network,
+    config: ReliableConfig,
+    epoch: u64,
+    outboxes: Arc<Mutex<HashMap<String, Outbox>>>,
+    delivered: Arc<tokio::sync::Mutex<HashMap<String, Delivered>>>,
+}
+
+/// How far the current incarnation of a sender has been delivered.
+#[derive(Debug, Default)]
+struct Delivered {
+    epoch: u64,
+    up_to: usize,
+}
+
+#[derive(Debug, Default)]
+struct Outbox {
+    last_seq: usize,
+    queue: VecDeque<(usize, Value)>,
+    ready: Arc<Notify>,
+}
+
+impl Reliable {
+    pub fn new(network: Network, config: ReliableConfig) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is before the unix epoch")
+            .as_nanos() as u64;
+        Self {
+            network,
+            config,
+            epoch,
+            outboxes: Default::default(),
+            delivered: Default::default(),
+        }
+    }
+
+    /// Queues `payload` for `dest`. It is delivered to `dest` at least once,
+    /// after everything queued for `dest` before it.
+    pub fn send<TPayload: Payload>(&self, dest: &str, payload: TPayload) {
+        let payload = serde_json::to_value(payload).expect("Should be able to serialize payload");
+        let mut outboxes = self.outboxes.lock().expect("Unable to lock outboxes");
+        let outbox = outboxes.entry(dest.to_string()).or_insert_with(|| {
+            let outbox = Outbox::default();
+            let sender = self
+                .clone()
+                .run_link(dest.to_string(), outbox.ready.clone());
+            let task = tokio::spawn(sender.in_current_span());
+            self.network.timers().track(task.abort_handle());
+            outbox
+        });
+        outbox.last_seq += 1;
+        outbox.queue.push_back((outbox.last_seq, payload));
+        outbox.ready.notify_one();
+    }
+
+    /// How many messages are still waiting for an ack from `dest`.
+    pub fn outstanding(&self, dest: &str) -> usize {
+        let outboxes = self.outboxes.lock().expect("Unable to lock outboxes");
+        outboxes.get(dest).map_or(0, |outbox| outbox.queue.len())
+    }
+
+    /// Hands the messages of `msg` that were not delivered before to
+    /// `deliver`, in order, then acks them. A message is only acked, and so
+    /// never retransmitted, once `deliver` has succeeded for it. Batches from
+    /// an earlier incarnation of the sender are dropped unacked.
+    pub async fn receive<TDeliver, TFuture>(
+        &self,
+        msg: Message<Deliver>,
+        mut deliver: TDeliver,
+    ) -> anyhow::Result<()>
+    where
+        TDeliver: FnMut(Message<Value>) -> TFuture,
+        TFuture: Future<Output = anyhow::Result<()>>,
+    {
+        let up_to = {
+            // Held while delivering so a retransmitted batch racing the
+            // original cannot deliver the same message twice.
+            let mut delivered = self.delivered.lock().await;
+            let link = delivered.entry(msg.src.clone()).or_default();
+            let epoch = msg.body.payload.epoch;
+            if epoch < link.epoch {
+                debug!(src = %msg.src, epoch, "dropping batch from an earlier incarnation");
+                return Ok(());
+            }
+            if epoch > link.epoch {
+                *link = Delivered { epoch, up_to: 0 };
+            }
+            for (seq, payload) in &msg.body.payload.messages {
+                if *seq <= link.up_to {
+                    continue;
+                }
+                deliver(Message::new(
+                    msg.src.clone(),
+                    msg.dest.clone(),
+                    payload.clone(),
+                ))
+                .await?;
+                link.up_to = *seq;
+            }
+            link.up_to
+        };
+        self.network.send(&msg.reply(DeliverOk { up_to })).await;
+        Ok(())
+    }
+
+    async fn run_link(self, dest: String, ready: Arc<Notify>) {
+        let mut backoff = self.config.min_backoff;
+        loop {
+            let messages: Vec<_> = {
+                let outboxes = self.outboxes.lock().expect("Unable to lock outboxes");
+                outboxes[&dest]
+                    .queue
+                    .iter()
+                    .take(self.config.max_batch)
+                    .cloned()
+                    .collect()
+            };
+            if messages.is_empty() {
+                ready.notified().await;
+                continue;
+            }
+
+            let deliver = Deliver {
+                epoch: self.epoch,
+                messages,
+            };
+            match self
+                .network
+                .call_with_timeout(&dest, deliver, self.config.ack_timeout)
+                .await
+            {
+                Ok(DeliverOk { up_to }) => {
+                    let mut outboxes = self.outboxes.lock().expect("Unable to lock outboxes");
+                    let queue = &mut outboxes.get_mut(&dest).expect("Outbox exists").queue;
+                    while queue.front().is_some_and(|(seq, _)| *seq <= up_to) {
+                        queue.pop_front();
+                    }
+                    backoff = self.config.min_backoff;
+                }
+                Err(e) => {
+                    debug!(%dest, error = %e, ?backoff, "delivery failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Timers;
+    use serde_json::json;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn reliable() -> (Reliable, Receiver<String>) {
+        let (tx, rx) = mpsc::channel(16);
+        let network = Network::new("n0".to_string(), tx, Timers::new(0));
+        (Reliable::new(network, ReliableConfig::default()), rx)
+    }
+
+    async fn next_sent(outbound: &mut Receiver<String>) -> Message<Value> {
+        let line = outbound.recv().await.expect("A message was sent");
+        serde_json::from_str(&line).expect("Sent messages are valid")
+    }
+
+    fn batch(epoch: u64, messages: &[(usize, &str)]) -> Message<Deliver> {
+        let messages = messages.iter().map(|(seq, v)| (*seq, json!(v))).collect();
+        let mut msg = Message::new(
+            "n1".to_string(),
+            "n0".to_string(),
+            Deliver { epoch, messages },
+        );
+        msg.with_id(7);
+        msg
+    }
+
+    async fn receive(reliable: &Reliable, msg: Message<Deliver>) -> Vec<Value> {
+        let mut delivered = Vec::new();
+        reliable
+            .receive(msg, |msg| {
+                delivered.push(msg.body.payload);
+                std::future::ready(Ok(()))
+            })
+            .await
+            .unwrap();
+        delivered
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacked_messages_are_retransmitted_until_acked() {
+        let (reliable, mut outbound) = reliable();
+        reliable.send("n1", json!("a"));
+        let first = next_sent(&mut outbound).await;
+        let retry = next_sent(&mut outbound).await;
+        assert_eq!(first.body.payload["messages"], json!([[1, "a"]]));
+        assert_eq!(retry.body.payload, first.body.payload);
+        assert_eq!(reliable.outstanding("n1"), 1);
+
+        let reply = retry.reply(json!({ "type": "reliable_ok", "up_to": 1 }));
+        reliable
+            .network
+            .get_reply_channel(&retry.body.msg_id.unwrap())
+            .expect("The retry is waiting")
+            .send(serde_json::to_string(&reply).unwrap())
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(reliable.outstanding("n1"), 0);
+    }
+
+    #[tokio::test]
+    async fn redelivered_messages_are_dropped_and_acked() {
+        let (reliable, mut outbound) = reliable();
+        let delivered = receive(&reliable, batch(1, &[(1, "a"), (2, "b")])).await;
+        assert_eq!(delivered, [json!("a"), json!("b")]);
+        assert_eq!(next_sent(&mut outbound).await.body.payload["up_to"], 2);
+
+        let delivered = receive(&reliable, batch(1, &[(2, "b"), (3, "c")])).await;
+        assert_eq!(delivered, [json!("c")]);
+        assert_eq!(next_sent(&mut outbound).await.body.payload["up_to"], 3);
+    }
+
+    #[tokio::test]
+    async fn a_restarted_sender_is_delivered_from_the_start() {
+        let (reliable, mut outbound) = reliable();
+        receive(&reliable, batch(1, &[(1, "a"), (2, "b")])).await;
+        next_sent(&mut outbound).await;
+
+        let delivered = receive(&reliable, batch(2, &[(1, "c")])).await;
+        assert_eq!(delivered, [json!("c")]);
+        assert_eq!(next_sent(&mut outbound).await.body.payload["up_to"], 1);
+
+        // A late retransmit from the old incarnation is neither delivered
+        // nor acked.
+        let delivered = receive(&reliable, batch(1, &[(3, "d")])).await;
+        assert!(delivered.is_empty());
+        assert!(outbound.try_recv().is_err());
+    }
+
+    #[test]
+    fn restarts_get_a_later_epoch() {
+        let (first, _) = reliable();
+        std::thread::sleep(Duration::from_millis(1));
+        let (second, _) = reliable();
+        assert!(second.epoch > first.epoch);
+    }
+}
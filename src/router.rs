@@ -0,0 +1,98 @@
+use crate::{ErrorCode, MaelstromError, Message, Payload};
+use serde_json::Value;
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+use tracing::debug;
+
+type Handler<TState> = Box<
+    dyn Fn(TState, Message<Value>) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Dispatches messages to async handlers by their `type`.
+///
+/// A handler receives a clone of the node state and the message with its
+/// payload parsed into the handler's payload type, which can be a struct for
+/// a single message type or a whole payload enum. Requests with an unrouted
+/// type fail with a not-supported error, which the runtime sends back to the
+/// client; unrouted replies are logged and dropped.
+pub struct Router<TState> {
+    routes: HashMap<String, Handler<TState>>,
+}
+
+impl<TState> Default for Router<TState> {
+    fn default() -> Self {
+        Self {
+            routes: Default::default(),
+        }
+    }
+}
+
+impl<TState> Router<TState>
+where
+    TState: Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<TPayload, THandler, TFuture>(
+        mut self,
+        message_type: &str,
+        handler: THandler,
+    ) -> Self
+    where
+        TPayload: Payload,
+        THandler: Fn(TState, Message<TPayload>) -> TFuture + Send + Sync + 'static,
+        TFuture: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let handler: Handler<TState> = Box::new(move |state, msg| match msg.into_typed() {
+            Ok(msg) => Box::pin(handler(state, msg)),
+            Err(e) => Box::pin(async move {
+                Err(MaelstromError::new(ErrorCode::MalformedRequest, e.to_string()).into())
+            }),
+        });
+        self.routes.insert(message_type.to_string(), handler);
+        self
+    }
+
+    /// Adds all routes of `other`.
+    pub fn merge(mut self, other: Router<TState>) -> Self {
+        self.routes.extend(other.routes);
+        self
+    }
+
+    /// Adds all routes of `other`, whose handlers get the state `project`
+    /// picks out of ours. This lets one node serve several workloads, each
+    /// with its own state and router.
+    pub fn merge_with<TOther, TProject>(mut self, other: Router<TOther>, project: TProject) -> Self
+    where
+        TOther: Send + 'static,
+        TProject: Fn(&TState) -> TOther + Send + Sync + 'static,
+    {
+        let project = Arc::new(project);
+        for (message_type, handler) in other.routes {
+            let project = project.clone();
+            let handler: Handler<TState> =
+                Box::new(move |state, msg| handler(project(&state), msg));
+            self.routes.insert(message_type, handler);
+        }
+        self
+    }
+
+    pub async fn dispatch(&self, state: TState, msg: Message<Value>) -> anyhow::Result<()> {
+        let message_type = msg.get_payload()["type"].as_str().unwrap_or_default();
+        match self.routes.get(message_type) {
+            Some(handler) => handler(state, msg).await,
+            None if msg.is_reply() => {
+                debug!(src = %msg.src, r#type = message_type, "dropping unexpected reply");
+                Ok(())
+            }
+            None => Err(MaelstromError::new(
+                ErrorCode::NotSupported,
+                format!("Message type {message_type:?} is not supported"),
+            )
+            .into()),
+        }
+    }
+}
@@ -1,15 +1,33 @@
+use crate::dedup::Seen;
+use crate::failure_detector::Heartbeat;
 use crate::message::Body;
-use crate::Network;
+#[cfg(unix)]
+use crate::UnixTransport;
+use crate::{
+    init_logging, ErrorCode, FailureDetector, FailureDetectorConfig, MaelstromError, Network,
+    StdioTransport, TcpTransport, Timers, Transport, TransportReader, TransportWriter,
+};
 use crate::{
     message::{Message, Payload},
     node::Node,
 };
 use anyhow::Context;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{value::MapDeserializer, DeserializeOwned},
+    Deserialize, Serialize,
+};
 use serde_json::Value;
-use std::{fmt::Debug, marker::PhantomData};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::mpsc::channel;
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    io::ErrorKind,
+    marker::PhantomData,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::sync::{oneshot, Semaphore};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 pub struct Runtime<TPayload, TNode>(PhantomData<TPayload>, PhantomData<TNode>)
 where
@@ -21,98 +39,530 @@ where
     TPayload: Payload,
     TNode: Node<TPayload> + 'static + Clone,
 {
+    /// Serves the node over stdin/stdout, or over the socket named by
+    /// `GOSSIP_CONNECT` (`host:port` or `unix:/path`) when it is set, such
+    /// as the one the `hub` binary listens on.
     pub async fn run() -> anyhow::Result<()> {
-        let stdin = tokio::io::stdin();
-        let mut stdin_lines = BufReader::new(stdin).lines();
-
-        let init_msg = &stdin_lines.next_line().await?.expect("First message");
-        let init_msg = serde_json::from_str(init_msg).expect("Must be init");
-        let Message {
-            body:
-                Body {
-                    payload:
-                        InitializationPayload::Init {
-                            ref node_id,
-                            ref node_ids,
-                        },
-                    ..
-                },
-            ..
-        } = init_msg
-        else {
-            panic!("first message not init")
-        };
-
-        let (stdin_tx, mut stdin_rx) = channel(1);
-        let (stdout_tx, mut stdout_rx) = channel(1);
-
-        let network = Network::new(stdout_tx);
-        let node = TNode::from_init(node_id.clone(), node_ids.clone(), network.clone());
+        Self::run_with_config(RuntimeConfig::from_env()).await
+    }
 
-        let reply = init_msg.reply(InitializationPayload::InitOk);
-        {
-            let mut stdout = tokio::io::stdout();
-            let reply = serde_json::to_string(&reply).context("Serialize init_ok")?;
-            stdout
-                .write_all(reply.as_bytes())
-                .await
-                .expect("Writing to stdout");
-            stdout.write_all(b"\n").await.expect("Writing to stdout");
-            stdout
-                .flush()
-                .await
-                .expect("Unable to send init_ok msg to stdout: {error}");
+    pub async fn run_with_config(config: RuntimeConfig) -> anyhow::Result<()> {
+        init_logging();
+        let Ok(addr) = std::env::var("GOSSIP_CONNECT") else {
+            return Self::serve(config, StdioTransport::stdio()).await;
+        };
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Self::serve(config, UnixTransport::connect(path).await?).await;
         }
+        Self::serve(config, TcpTransport::connect(addr).await?).await
+    }
 
-        tokio::spawn(async move {
-            while let Some(msg) = stdout_rx.recv().await {
-                eprintln!("Out: {msg}");
-                let mut stdout = tokio::io::stdout();
-                stdout.write_all(msg.as_bytes()).await?;
-                stdout.write_all(b"\n").await?;
-                stdout.flush().await?;
+    pub async fn serve<TTransport: Transport>(
+        config: RuntimeConfig,
+        transport: TTransport,
+    ) -> anyhow::Result<()> {
+        let (mut reader, mut writer) = transport.split();
+        let (inbound_tx, inbound_rx) = channel(config.inbound_queue);
+        let (outbound_tx, mut outbound_rx) = channel::<String>(config.outbound_queue);
+
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        let writer = tokio::spawn(async move {
+            loop {
+                let msg = tokio::select! {
+                    biased;
+                    msg = outbound_rx.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = &mut stop_rx => break,
+                };
+                writer.send(msg).await?;
+                // Only flush once the queue is drained, so bursts of replies
+                // go out in one write instead of one syscall per line.
+                while let Ok(msg) = outbound_rx.try_recv() {
+                    writer.send(msg).await?;
+                }
+                writer.flush().await?;
             }
-            tokio::io::Result::Ok(())
+            std::io::Result::Ok(())
         });
 
         tokio::spawn(async move {
-            let stdin = tokio::io::stdin();
-            let mut lines = tokio::io::BufReader::new(stdin).lines();
-
-            while let Some(line) = lines.next_line().await.expect("Malformed new line message") {
-                let msg = serde_json::from_str(&line);
-                let msg: Message<Value> = msg.expect("Malformed message");
-                eprintln!("In : {line}");
-                if let Some(reply_channel) = msg
-                    .body
-                    .in_reply_to
-                    .and_then(|msg_id| network.get_reply_channel(&msg_id))
-                {
-                    // eprintln!("reply channel {msg_id}");
-                    if let Err(e) = reply_channel.send(line) {
-                        eprintln!("Unable to send to rpc handler: {e:?}");
+            loop {
+                let line = match reader.recv().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        warn!(error = %e, "dropping line that is not valid UTF-8");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(error = %e, "unable to read from transport");
+                        break;
                     }
-                } else {
-                    stdin_tx
-                        .send(msg)
-                        .await
-                        .expect("Unable to send out message");
                 };
+                if inbound_tx.send(line).await.is_err() {
+                    break;
+                }
             }
         });
 
-        while let Some(msg) = stdin_rx.recv().await {
-            let msg = msg.into_typed()?;
+        let result = Self::run_node(config, inbound_rx, outbound_tx).await;
+        // Background tasks may still hold the outbound queue, so stop the
+        // writer explicitly once everything the node queued has gone out.
+        let _ = stop_tx.send(());
+        if let Ok(Err(e)) = writer.await {
+            error!(error = %e, "unable to write to transport");
+        }
+        result
+    }
+
+    /// The first inbound line must be the `init` message.
+    async fn run_node(
+        config: RuntimeConfig,
+        mut inbound: Receiver<String>,
+        outbound: Sender<String>,
+    ) -> anyhow::Result<()> {
+        let (init_msg, node_id, node_ids) = loop {
+            let Some(line) = inbound.recv().await else {
+                return Ok(());
+            };
+            let msg = match parse_line(&line) {
+                Ok(msg) => msg,
+                Err(reply) => {
+                    if let Some(reply) = reply {
+                        send_before_init(&outbound, &reply).await?;
+                    }
+                    continue;
+                }
+            };
+            if let Ok(Message {
+                body:
+                    Body {
+                        payload: InitializationPayload::Init { node_id, node_ids },
+                        ..
+                    },
+                ..
+            }) = msg.clone().into_typed()
+            {
+                break (msg, node_id, node_ids);
+            }
+
+            warn!(body = %line, "dropping message received before init");
+            let error = MaelstromError::new(
+                ErrorCode::TemporarilyUnavailable,
+                "node is not initialized yet",
+            );
+            if let Some(reply) = error_reply(&msg, error) {
+                send_before_init(&outbound, &reply).await?;
+            }
+        };
+
+        let timers = config.seed.map(Timers::new).unwrap_or_default();
+        let mut network = Network::new(node_id.clone(), outbound.clone(), timers);
+        if config.dedup_window > 0 {
+            network = network.with_dedup(config.dedup_window);
+        }
+        if let Some(detector_config) = config.failure_detector.clone() {
+            let peers: Vec<String> = node_ids
+                .iter()
+                .filter(|id| **id != node_id)
+                .cloned()
+                .collect();
+            network = network.with_failure_detector(FailureDetector::new(detector_config, &peers));
+        }
+        let node_span = info_span!("node", node_id = %node_id);
+        let node = node_span
+            .in_scope(|| TNode::from_init(node_id.clone(), node_ids.clone(), network.clone()));
+
+        network
+            .send(&init_msg.reply(InitializationPayload::InitOk))
+            .await;
+        if let Some(detector) = network.failure_detector() {
+            node_span.in_scope(|| detector.start(&network));
+        }
+
+        // Replies are handed to their waiting rpc right away, and the reader
+        // never waits for a free handler slot itself: under `Wait`, requests
+        // that don't fit the queue are staged while it keeps reading, so the
+        // replies that busy handlers wait for still get through.
+        let (requests_tx, mut requests_rx) = channel::<Message<Value>>(config.inbound_queue);
+        let reader_network = network.clone();
+        let reader_span = node_span.clone();
+        tokio::spawn(
+            async move {
+                let mut staged = VecDeque::new();
+                loop {
+                    let line = tokio::select! {
+                        line = inbound.recv() => match line {
+                            Some(line) => line,
+                            None => break,
+                        },
+                        permit = requests_tx.reserve(), if !staged.is_empty() => {
+                            let Ok(permit) = permit else {
+                                return;
+                            };
+                            permit.send(staged.pop_front().expect("Staged requests are empty"));
+                            continue;
+                        }
+                    };
+                    let msg = match parse_line(&line) {
+                        Ok(msg) => msg,
+                        Err(reply) => {
+                            if let Some(reply) = reply {
+                                reader_network.send(&reply).await;
+                            }
+                            continue;
+                        }
+                    };
+                    debug!(
+                        src = %msg.src,
+                        msg_id = msg.body.msg_id,
+                        in_reply_to = msg.body.in_reply_to,
+                        r#type = msg.get_payload()["type"].as_str(),
+                        "received"
+                    );
+                    trace!(body = %line, "received");
+                    // Heartbeats only feed the failure detector. Without one
+                    // the type is the node's to handle.
+                    if let Some(detector) = reader_network.failure_detector() {
+                        if msg.get_payload()["type"] == Heartbeat::TYPE {
+                            detector.heartbeat(&msg.src);
+                            continue;
+                        }
+                    }
+                    if let Some(reply_channel) = msg
+                        .body
+                        .in_reply_to
+                        .and_then(|msg_id| reader_network.get_reply_channel(&msg_id))
+                    {
+                        if let Err(e) = reply_channel.send(line) {
+                            debug!(reply = %e, "rpc caller is gone, dropping reply");
+                        }
+                        continue;
+                    }
+
+                    match config.overload {
+                        OverloadPolicy::Wait => staged.push_back(msg),
+                        OverloadPolicy::Reject => match requests_tx.try_send(msg) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(msg)) => {
+                                warn!(
+                                    src = %msg.src,
+                                    msg_id = msg.body.msg_id,
+                                    "node is overloaded, rejecting"
+                                );
+                                let error = MaelstromError::new(
+                                    ErrorCode::TemporarilyUnavailable,
+                                    "node is overloaded",
+                                );
+                                if let Some(reply) = error_reply(&msg, error) {
+                                    reader_network.send(&reply).await;
+                                }
+                            }
+                            Err(TrySendError::Closed(_)) => return,
+                        },
+                    }
+                }
+                // The input is closed; the staged requests are still handled.
+                for msg in staged {
+                    if requests_tx.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .instrument(reader_span),
+        );
+
+        node.on_start().instrument(node_span.clone()).await?;
+
+        let terminated = terminated();
+        tokio::pin!(terminated);
+        let in_flight = Arc::new(Semaphore::new(config.max_in_flight));
+        loop {
+            let msg = tokio::select! {
+                msg = requests_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = &mut terminated => {
+                    info!(parent: &node_span, "received SIGTERM, shutting down");
+                    break;
+                }
+            };
+            if let (Some(dedup), Some(msg_id), false) =
+                (network.dedup(), msg.body.msg_id, msg.is_reply())
+            {
+                match dedup.check(&msg.src, msg_id) {
+                    Seen::New => {}
+                    Seen::InFlight => {
+                        debug!(parent: &node_span, src = %msg.src, msg_id, "dropping duplicate request");
+                        continue;
+                    }
+                    Seen::Replied(payload) => {
+                        debug!(parent: &node_span, src = %msg.src, msg_id, "replaying cached reply");
+                        network.send(&msg.reply(payload)).await;
+                        continue;
+                    }
+                }
+            }
+            let permit = in_flight.clone().acquire_owned().await?;
+            let request = Message {
+                src: msg.src.clone(),
+                dest: msg.dest.clone(),
+                body: Body {
+                    msg_id: msg.body.msg_id,
+                    in_reply_to: msg.body.in_reply_to,
+                    payload: (),
+                },
+            };
+            let span = info_span!(
+                parent: &node_span,
+                "handle",
+                src = %msg.src,
+                dest = %msg.dest,
+                msg_id = msg.body.msg_id,
+                in_reply_to = msg.body.in_reply_to,
+                r#type = msg.get_payload()["type"].as_str(),
+            );
+            let message_type = msg.get_payload()["type"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let msg = match msg.into_typed() {
+                Ok(msg) => msg,
+                Err(e) => {
+                    span.in_scope(|| warn!(error = %e, "unable to parse message payload"));
+                    let code = if is_known_type::<TPayload>(&message_type) {
+                        ErrorCode::MalformedRequest
+                    } else {
+                        ErrorCode::NotSupported
+                    };
+                    if let Some(reply) =
+                        error_reply(&request, MaelstromError::new(code, e.to_string()))
+                    {
+                        network.send(&reply).await;
+                    }
+                    continue;
+                }
+            };
             let node = node.clone();
-            tokio::spawn(async move {
-                let _ = node
-                    .handle_message(msg)
-                    .await
-                    .inspect_err(|e| eprintln!("Got error when handling message: {e}"));
-            });
+            let network = network.clone();
+            tokio::spawn(
+                async move {
+                    let _permit = permit;
+                    if let Err(e) = node.handle_message(msg).await {
+                        warn!(error = %e, "handler failed");
+                        if let Some(reply) = e
+                            .downcast_ref::<MaelstromError>()
+                            .and_then(|error| error_reply(&request, error.clone()))
+                        {
+                            network.send(&reply).await;
+                        }
+                    }
+                    if let (Some(dedup), Some(msg_id)) = (network.dedup(), request.body.msg_id) {
+                        dedup.finish(&request.src, msg_id);
+                    }
+                }
+                .instrument(span),
+            );
         }
 
-        Ok(())
+        let drained = in_flight.acquire_many(config.max_in_flight as u32);
+        if tokio::time::timeout(config.drain_timeout, drained)
+            .await
+            .is_err()
+        {
+            warn!(parent: &node_span, "timed out waiting for in-flight handlers to finish");
+        }
+        let result = node.on_shutdown().instrument(node_span).await;
+        network.timers().cancel_all();
+        result
+    }
+}
+
+/// Parses an inbound line. A line that is not a valid message fails with
+/// the malformed-request error to answer it with, if it still names a sender
+/// and a msg_id.
+fn parse_line(line: &str) -> Result<Message<Value>, Option<Message<MaelstromError>>> {
+    let error = match serde_json::from_str(line) {
+        Ok(msg) => return Ok(msg),
+        Err(error) => error,
+    };
+    warn!(error = %error, body = %line, "dropping malformed message");
+
+    let reply = || {
+        let value: Value = serde_json::from_str(line).ok()?;
+        let request = Message {
+            src: value["src"].as_str()?.to_string(),
+            dest: value["dest"].as_str()?.to_string(),
+            body: Body {
+                msg_id: Some(value["body"]["msg_id"].as_u64()? as usize),
+                in_reply_to: None,
+                payload: (),
+            },
+        };
+        let error = MaelstromError::new(ErrorCode::MalformedRequest, error.to_string());
+        Some(request.reply(error))
+    };
+    Err(reply())
+}
+
+/// Whether `TPayload` has a variant for `message_type`, whatever else the
+/// message carries. Only the tag is deserialized, with an error type that
+/// records whether serde rejected it as an unknown variant.
+fn is_known_type<TPayload: DeserializeOwned>(message_type: &str) -> bool {
+    let tag = MapDeserializer::<_, TagError>::new(std::iter::once(("type", message_type)));
+    !matches!(TPayload::deserialize(tag), Err(TagError::UnknownType))
+}
+
+#[derive(Debug)]
+enum TagError {
+    UnknownType,
+    Other,
+}
+
+impl Display for TagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagError::UnknownType => write!(f, "unknown message type"),
+            TagError::Other => write!(f, "invalid message"),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+impl serde::de::Error for TagError {
+    fn custom<T: Display>(_msg: T) -> Self {
+        TagError::Other
+    }
+
+    fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+        TagError::UnknownType
+    }
+}
+
+/// Until init there is no `Network` to number messages, so error replies
+/// sent before it go out without a msg_id.
+async fn send_before_init(
+    outbound: &Sender<String>,
+    reply: &Message<MaelstromError>,
+) -> anyhow::Result<()> {
+    let reply = serde_json::to_string(reply).context("Serialize error reply")?;
+    outbound.send(reply).await?;
+    Ok(())
+}
+
+/// The reply carrying `error`, unless `msg` has no msg_id to answer or is
+/// itself a reply.
+fn error_reply<T>(msg: &Message<T>, error: MaelstromError) -> Option<Message<MaelstromError>> {
+    (msg.body.msg_id.is_some() && !msg.is_reply()).then(|| msg.reply(error))
+}
+
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(_) => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    std::future::pending().await
+}
+
+/// What the runtime does with a request once `max_in_flight` handlers are
+/// running and `inbound_queue` requests are already waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Hold the request until a handler finishes. Input is still read
+    /// meanwhile, so replies to the handlers' rpcs get through.
+    Wait,
+    /// Answer the request with a temporarily-unavailable error.
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct RuntimeConfig {
+    pub max_in_flight: usize,
+    pub inbound_queue: usize,
+    pub outbound_queue: usize,
+    pub overload: OverloadPolicy,
+    /// How long shutdown waits for in-flight handlers before `on_shutdown`.
+    pub drain_timeout: Duration,
+    /// Seeds the jitter of the node's timers; random when `None`.
+    pub seed: Option<u64>,
+    /// How many recent requests to remember replies for, so redelivered
+    /// requests are answered from the cache instead of handled again. Replies
+    /// sent after the handler returns are not cached. `0` disables it.
+    pub dedup_window: usize,
+    /// Heartbeats peers to track which ones are reachable, see
+    /// `Network::peer_status`. Off when `None`.
+    pub failure_detector: Option<FailureDetectorConfig>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 256,
+            inbound_queue: 1024,
+            outbound_queue: 1024,
+            overload: OverloadPolicy::Wait,
+            drain_timeout: Duration::from_secs(5),
+            seed: None,
+            dedup_window: 0,
+            failure_detector: None,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Reads overrides of the defaults from `GOSSIP_MAX_IN_FLIGHT`,
+    /// `GOSSIP_INBOUND_QUEUE`, `GOSSIP_OUTBOUND_QUEUE`, `GOSSIP_OVERLOAD`
+    /// (`wait` or `reject`), `GOSSIP_DRAIN_TIMEOUT_MS`, `GOSSIP_DEDUP_WINDOW`
+    /// and `GOSSIP_HEARTBEAT_MS`, which turns on the failure detector.
+    /// Handler and queue limits are kept to at least 1, and to no more than
+    /// a tokio semaphore holds. Heartbeats go out at most every millisecond.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let number = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        // `acquire_many` takes the handler limit as a u32 when draining.
+        let max_limit = Semaphore::MAX_PERMITS.min(u32::MAX as usize);
+        let limit = |name: &str, default: usize| number(name, default).clamp(1, max_limit);
+        let overload = match std::env::var("GOSSIP_OVERLOAD").as_deref() {
+            Ok("reject") => OverloadPolicy::Reject,
+            _ => default.overload,
+        };
+        Self {
+            max_in_flight: limit("GOSSIP_MAX_IN_FLIGHT", default.max_in_flight),
+            inbound_queue: limit("GOSSIP_INBOUND_QUEUE", default.inbound_queue),
+            outbound_queue: limit("GOSSIP_OUTBOUND_QUEUE", default.outbound_queue),
+            overload,
+            drain_timeout: Duration::from_millis(number(
+                "GOSSIP_DRAIN_TIMEOUT_MS",
+                default.drain_timeout.as_millis() as usize,
+            ) as u64),
+            seed: default.seed,
+            dedup_window: number("GOSSIP_DEDUP_WINDOW", default.dedup_window),
+            failure_detector: std::env::var("GOSSIP_HEARTBEAT_MS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(|millis: u64| FailureDetectorConfig {
+                    heartbeat_interval: Duration::from_millis(millis.max(1)),
+                    ..Default::default()
+                }),
+        }
     }
 }
 
@@ -126,3 +576,25 @@ enum InitializationPayload {
     },
     InitOk,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    #[allow(dead_code)]
+    enum Payload {
+        Echo { echo: String },
+        Generate,
+    }
+
+    #[test]
+    fn known_types_are_told_apart_from_unknown_ones() {
+        assert!(is_known_type::<Payload>("echo"));
+        assert!(is_known_type::<Payload>("generate"));
+        assert!(!is_known_type::<Payload>("broadcast"));
+        assert!(is_known_type::<Value>("broadcast"));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{FailureDetector, Network, Timers};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::Instrument;
+
+/// Which target the gossip timing is tuned for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GossipProfile {
+    /// Spread values fast, at the cost of more, smaller messages.
+    Latency,
+    /// Batch values into fewer messages, at the cost of slower spread.
+    Efficiency,
+}
+
+#[derive(Clone, Debug)]
+pub struct GossipConfig {
+    /// How long new values wait for more to batch with.
+    pub batch_window: Duration,
+    /// A full batch is flushed right away.
+    pub max_batch: usize,
+    /// The tick interval while there is anything to gossip.
+    pub min_interval: Duration,
+    /// Idle ticks back off exponentially up to this interval.
+    pub max_interval: Duration,
+    /// How often to run an anti-entropy round, which repairs what gossip
+    /// missed.
+    pub anti_entropy_interval: Duration,
+}
+
+impl GossipConfig {
+    pub fn for_profile(profile: GossipProfile) -> Self {
+        match profile {
+            GossipProfile::Latency => Self {
+                batch_window: Duration::from_millis(20),
+                max_batch: 64,
+                min_interval: Duration::from_millis(150),
+                max_interval: Duration::from_secs(1),
+                anti_entropy_interval: Duration::from_secs(1),
+            },
+            GossipProfile::Efficiency => Self {
+                batch_window: Duration::from_millis(300),
+                max_batch: 512,
+                min_interval: Duration::from_millis(500),
+                max_interval: Duration::from_secs(2),
+                anti_entropy_interval: Duration::from_secs(3),
+            },
+        }
+    }
+
+    /// Reads `GOSSIP_PROFILE`: `latency` (the default) or `efficiency`.
+    pub fn from_env() -> Self {
+        let profile = match std::env::var("GOSSIP_PROFILE").as_deref() {
+            Ok("efficiency") => GossipProfile::Efficiency,
+            _ => GossipProfile::Latency,
+        };
+        Self::for_profile(profile)
+    }
+}
+
+/// Decides when to gossip. New values are batched for `batch_window`, or
+/// until `max_batch` of them are waiting, then flushed. After that the
+/// scheduler keeps ticking, so unacked values are sent again, and backs off
+/// while ticks find nothing to send.
+///
+/// It also counts client ops and the messages sent for them, since
+/// msgs-per-op is what the efficiency targets are measured in. Failure
+/// detector heartbeats count too, once `with_heartbeats` is set.
+#[derive(Clone, Debug)]
+pub struct GossipScheduler {
+    pending: Arc<AtomicUsize>,
+    wake: Arc<Notify>,
+    ops: Arc<AtomicUsize>,
+    messages: Arc<AtomicUsize>,
+    heartbeats: Option<FailureDetector>,
+    max_batch: usize,
+}
+
+impl GossipScheduler {
+    /// Starts calling `gossip`, which returns how many messages it sent, on
+    /// the schedule described above. It runs until the timers are cancelled.
+    pub fn start<TGossip, TFuture>(
+        timers: &Timers,
+        config: GossipConfig,
+        mut gossip: TGossip,
+    ) -> Self
+    where
+        TGossip: FnMut() -> TFuture + Send + 'static,
+        TFuture: Future<Output = usize> + Send + 'static,
+    {
+        let scheduler = Self {
+            pending: Default::default(),
+            wake: Default::default(),
+            ops: Default::default(),
+            messages: Default::default(),
+            heartbeats: None,
+            max_batch: config.max_batch,
+        };
+
+        let this = scheduler.clone();
+        let task = tokio::spawn(
+            async move {
+                let mut interval = config.min_interval;
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(interval) => {}
+                        _ = this.wake.notified() => {
+                            if this.pending.load(Ordering::SeqCst) < config.max_batch {
+                                tokio::select! {
+                                    _ = tokio::time::sleep(config.batch_window) => {}
+                                    _ = this.wake.notified() => {}
+                                }
+                            }
+                        }
+                    }
+
+                    let new_values = this.pending.swap(0, Ordering::SeqCst);
+                    let sent = gossip().await;
+                    this.record_sent(sent);
+                    interval = if new_values > 0 || sent > 0 {
+                        config.min_interval
+                    } else {
+                        (interval * 2).min(config.max_interval)
+                    };
+                }
+            }
+            .in_current_span(),
+        );
+        timers.track(task.abort_handle());
+        scheduler
+    }
+
+    /// Counts the heartbeats of `network`'s failure detector, if it has
+    /// one, among the messages sent.
+    pub fn with_heartbeats(mut self, network: &Network) -> Self {
+        self.heartbeats = network.failure_detector().cloned();
+        self
+    }
+
+    /// Reports `count` values that still need to be gossiped.
+    pub fn record_new(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let pending = self.pending.fetch_add(count, Ordering::SeqCst) + count;
+        // Wake the scheduler for the first value of a batch, to start the
+        // batch window, and once the batch is full.
+        if pending == count || pending >= self.max_batch {
+            self.wake.notify_one();
+        }
+    }
+
+    /// Reports a client request.
+    pub fn record_op(&self) {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reports messages sent to other nodes outside of `gossip`, like acks.
+    pub fn record_sent(&self, count: usize) {
+        self.messages.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn ops(&self) -> usize {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    pub fn messages(&self) -> usize {
+        let heartbeats = self
+            .heartbeats
+            .as_ref()
+            .map_or(0, FailureDetector::heartbeats_sent);
+        self.messages.load(Ordering::Relaxed) + heartbeats
+    }
+
+    pub fn msgs_per_op(&self) -> f64 {
+        self.messages() as f64 / self.ops().max(1) as f64
+    }
+}
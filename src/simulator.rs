@@ -0,0 +1,327 @@
+use crate::{
+    ChannelTransport, MaelstromError, Message, Network, Node, Payload, Request, RpcError, Runtime,
+    RuntimeConfig, Timers,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tracing::debug;
+
+const CLIENT_ID: &str = "c0";
+const KV_SERVICES: [&str; 3] = ["seq-kv", "lin-kv", "lww-kv"];
+
+/// Runs `future` on a single threaded runtime whose clock starts paused, so
+/// sleeps inside the simulation advance virtual time instead of waiting.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .expect("Unable to build simulation runtime")
+        .block_on(future)
+}
+
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    pub node_count: usize,
+    pub seed: u64,
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// How long `rpc` and `call` wait for a reply, in virtual time.
+    pub rpc_timeout: Duration,
+    pub runtime: RuntimeConfig,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            node_count: 3,
+            seed: 0,
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            rpc_timeout: Duration::from_secs(5),
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+/// An in-process cluster: every node runs its own `Runtime` and all traffic
+/// goes through an in-memory router that delays messages by a latency drawn
+/// from a seeded RNG. A client (`c0`) and the Maelstrom key/value services
+/// are simulated as well.
+pub struct Simulator<TPayload, TNode> {
+    node_ids: Vec<String>,
+    client: Network,
+    partitions: Arc<Mutex<HashSet<(String, String)>>>,
+    sent: Arc<Mutex<HashMap<String, usize>>>,
+    rpc_timeout: Duration,
+    _phantom: PhantomData<(TPayload, TNode)>,
+}
+
+impl<TPayload, TNode> Simulator<TPayload, TNode>
+where
+    TPayload: Payload,
+    TNode: Node<TPayload> + 'static + Clone,
+{
+    pub async fn start(config: SimulatorConfig) -> anyhow::Result<Self> {
+        let node_ids: Vec<String> = (0..config.node_count).map(|i| format!("n{i}")).collect();
+        let (wire_tx, wire_rx) = channel(1024);
+
+        let mut inboxes = HashMap::new();
+        for (i, node_id) in node_ids.iter().enumerate() {
+            let (inbox_tx, inbox_rx) = channel(1024);
+            inboxes.insert(node_id.clone(), inbox_tx);
+            let runtime = RuntimeConfig {
+                seed: Some(config.seed.wrapping_add(i as u64 + 1)),
+                ..config.runtime.clone()
+            };
+            tokio::spawn(Runtime::<TPayload, TNode>::serve(
+                runtime,
+                ChannelTransport::new(inbox_rx, wire_tx.clone()),
+            ));
+        }
+
+        let client = Network::new(CLIENT_ID.to_string(), wire_tx, Timers::new(config.seed));
+        let partitions: Arc<Mutex<HashSet<(String, String)>>> = Default::default();
+        let sent: Arc<Mutex<HashMap<String, usize>>> = Default::default();
+        let rpc_timeout = config.rpc_timeout;
+        let router = Router {
+            inboxes,
+            client: client.clone(),
+            partitions: partitions.clone(),
+            sent: sent.clone(),
+            kv: Default::default(),
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+        };
+        tokio::spawn(router.run(wire_rx));
+
+        let simulator = Self {
+            node_ids,
+            client,
+            partitions,
+            sent,
+            rpc_timeout,
+            _phantom: PhantomData,
+        };
+        for node_id in &simulator.node_ids {
+            let mut init = Message::new(
+                CLIENT_ID.to_string(),
+                node_id.clone(),
+                json!({
+                    "type": "init",
+                    "node_id": node_id,
+                    "node_ids": simulator.node_ids,
+                }),
+            );
+            simulator
+                .client
+                .rpc_with_timeout(&mut init, rpc_timeout)
+                .await?;
+        }
+
+        Ok(simulator)
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Sends `payload` from the client to `dest` and waits for the reply, up
+    /// to the configured `rpc_timeout`.
+    pub async fn rpc(&self, dest: &str, payload: TPayload) -> Result<Message<TPayload>, RpcError> {
+        self.rpc_with_timeout(dest, payload, self.rpc_timeout).await
+    }
+
+    /// Like `rpc`, but fails with `RpcError::Timeout` after `timeout`, for
+    /// example when `dest` is partitioned away from the client.
+    pub async fn rpc_with_timeout(
+        &self,
+        dest: &str,
+        payload: TPayload,
+        timeout: Duration,
+    ) -> Result<Message<TPayload>, RpcError> {
+        let mut msg = Message::new(CLIENT_ID.to_string(), dest.to_string(), payload);
+        self.client.rpc_with_timeout(&mut msg, timeout).await
+    }
+
+    /// Sends `request` from the client to `dest` and waits for its typed
+    /// response, up to the configured `rpc_timeout`.
+    pub async fn call<TRequest: Request>(
+        &self,
+        dest: &str,
+        request: TRequest,
+    ) -> Result<TRequest::Response, MaelstromError> {
+        self.client
+            .call_with_timeout(dest, request, self.rpc_timeout)
+            .await
+    }
+
+    /// Sends `payload` from the client to `dest` without waiting for a reply.
+    pub async fn send(&self, dest: &str, payload: TPayload) {
+        let msg = Message::new(CLIENT_ID.to_string(), dest.to_string(), payload);
+        self.client.send(&msg).await
+    }
+
+    /// Drops all traffic between nodes of different groups until `heal`.
+    pub fn partition(&self, groups: &[&[&str]]) {
+        let mut partitions = self.partitions.lock().expect("Unable to lock partitions");
+        for (i, group) in groups.iter().enumerate() {
+            for other in groups.iter().skip(i + 1) {
+                for a in group.iter() {
+                    for b in other.iter() {
+                        partitions.insert((a.to_string(), b.to_string()));
+                        partitions.insert((b.to_string(), a.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// How many messages of `message_type` have been sent so far, including
+    /// the ones a partition dropped.
+    pub fn sent(&self, message_type: &str) -> usize {
+        self.sent
+            .lock()
+            .expect("Unable to lock sent messages")
+            .get(message_type)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn heal(&self) {
+        self.partitions
+            .lock()
+            .expect("Unable to lock partitions")
+            .clear();
+    }
+}
+
+struct Router {
+    inboxes: HashMap<String, Sender<String>>,
+    client: Network,
+    partitions: Arc<Mutex<HashSet<(String, String)>>>,
+    sent: Arc<Mutex<HashMap<String, usize>>>,
+    kv: HashMap<&'static str, HashMap<String, Value>>,
+    rng: StdRng,
+    config: SimulatorConfig,
+}
+
+impl Router {
+    async fn run(mut self, mut wire: Receiver<String>) {
+        let inboxes = Arc::new(self.inboxes.clone());
+        while let Some(line) = wire.recv().await {
+            let Ok(msg) = serde_json::from_str::<Message<Value>>(&line) else {
+                debug!(%line, "simulator dropped malformed message");
+                continue;
+            };
+            if let Some(message_type) = msg.get_payload()["type"].as_str() {
+                *self
+                    .sent
+                    .lock()
+                    .expect("Unable to lock sent messages")
+                    .entry(message_type.to_string())
+                    .or_default() += 1;
+            }
+            let partitioned = self
+                .partitions
+                .lock()
+                .expect("Unable to lock partitions")
+                .contains(&(msg.src.clone(), msg.dest.clone()));
+            if partitioned {
+                continue;
+            }
+
+            let (dest, line) = match KV_SERVICES.iter().find(|s| **s == msg.dest) {
+                Some(service) => {
+                    let reply = self.handle_kv(service, &msg);
+                    (msg.src, reply.to_string())
+                }
+                None => (msg.dest, line),
+            };
+
+            let latency = self
+                .rng
+                .random_range(self.config.min_latency..=self.config.max_latency);
+            let inboxes = inboxes.clone();
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(latency).await;
+                deliver(&inboxes, &client, &dest, line).await;
+            });
+        }
+    }
+
+    fn handle_kv(&mut self, service: &'static str, msg: &Message<Value>) -> Value {
+        let store = self.kv.entry(service).or_default();
+        let payload = msg.get_payload();
+        let key = payload["key"].to_string();
+        let body = match payload["type"].as_str() {
+            Some("read") => match store.get(&key) {
+                Some(value) => json!({ "type": "read_ok", "value": value }),
+                None => json!({ "type": "error", "code": 20, "text": "key does not exist" }),
+            },
+            Some("write") => {
+                store.insert(key, payload["value"].clone());
+                json!({ "type": "write_ok" })
+            }
+            Some("cas") => {
+                let create = payload["create_if_not_exists"].as_bool().unwrap_or(false);
+                match store.get(&key) {
+                    Some(current) if *current == payload["from"] => {
+                        store.insert(key, payload["to"].clone());
+                        json!({ "type": "cas_ok" })
+                    }
+                    Some(_) => {
+                        json!({ "type": "error", "code": 22, "text": "current value does not match from" })
+                    }
+                    None if create => {
+                        store.insert(key, payload["to"].clone());
+                        json!({ "type": "cas_ok" })
+                    }
+                    None => {
+                        json!({ "type": "error", "code": 20, "text": "key does not exist" })
+                    }
+                }
+            }
+            _ => json!({ "type": "error", "code": 10, "text": "operation not supported" }),
+        };
+
+        let mut reply = json!({
+            "src": msg.dest,
+            "dest": msg.src,
+            "body": body,
+        });
+        reply["body"]["in_reply_to"] = json!(msg.body.msg_id);
+        reply
+    }
+}
+
+async fn deliver(
+    inboxes: &HashMap<String, Sender<String>>,
+    client: &Network,
+    dest: &str,
+    line: String,
+) {
+    if let Some(inbox) = inboxes.get(dest) {
+        let _ = inbox.send(line).await;
+        return;
+    }
+
+    let in_reply_to = serde_json::from_str::<Message<Value>>(&line)
+        .ok()
+        .and_then(|msg| msg.body.in_reply_to);
+    match in_reply_to.and_then(|msg_id| client.get_reply_channel(&msg_id)) {
+        Some(reply_channel) => {
+            let _ = reply_channel.send(line);
+        }
+        None => debug!(%dest, %line, "simulator dropped message"),
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Message, Network, RpcError};
+use crate::{Network, Request, RpcError};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{fmt::Debug, future::Future, marker::PhantomData};
 
@@ -13,24 +13,12 @@ pub trait Storage<TValue: Storable> {
         Self: Sync,
     {
         async {
-            let src = self.get_src();
-            let dest = self.get_type();
-            let msg = &mut Message::new(
-                src.to_string(),
-                dest.to_string(),
-                StoragePaylod::<TValue>::Read { key },
-            );
-            let response = self.get_network().rpc(msg).await.unwrap();
-            let value = match response.get_payload() {
-                StoragePaylod::ReadOk { value } => value,
-                StoragePaylod::Error { .. } => {
-                    return Err(RpcError::KeyDoesNotExist);
-                }
-                _ => {
-                    panic!("Received wrong message {response:?}");
-                }
+            let request = Read::<TValue> {
+                key,
+                _phantom: PhantomData,
             };
-            Ok(value.clone())
+            let ReadOk { value } = self.get_network().call(self.get_type(), request).await?;
+            Ok(value)
         }
     }
     fn set(&self, key: String, value: TValue) -> impl Future<Output = Result<(), RpcError>> + Send
@@ -38,18 +26,8 @@ pub trait Storage<TValue: Storable> {
         Self: Sync,
     {
         async {
-            let network = self.get_network();
-            let src = self.get_src();
-            let dest = self.get_type();
-            let msg = &mut Message::new(
-                src.to_string(),
-                dest.to_string(),
-                StoragePaylod::Write { key, value },
-            );
-            let reponse = network.rpc(msg).await.map_err(RpcError::Unknown)?;
-            let StoragePaylod::WriteOk = reponse.get_payload() else {
-                panic!("Received wrong message")
-            };
+            let request = Write { key, value };
+            self.get_network().call(self.get_type(), request).await?;
             Ok(())
         }
     }
@@ -63,63 +41,65 @@ pub trait Storage<TValue: Storable> {
         Self: Sync,
     {
         async {
-            let network = self.get_network();
-            let src = self.get_src();
-            let dest = self.get_type();
-            let msg = &mut Message::new(
-                src.to_string(),
-                dest.to_string(),
-                StoragePaylod::Cas {
-                    key,
-                    from,
-                    to,
-                    create_if_not_exists: true,
-                },
-            );
-            let response = network.rpc(msg).await.map_err(RpcError::Unknown)?;
-            match response.get_payload() {
-                StoragePaylod::CasOk => (),
-                StoragePaylod::Error { .. } => {
-                    return Err(RpcError::CasFail);
-                }
-                _ => {
-                    panic!("Received wrong message {response:?}");
-                }
+            let request = Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists: true,
             };
+            self.get_network().call(self.get_type(), request).await?;
             Ok(())
         }
     }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum StoragePaylod<TValue: Serialize + Send> {
-    Read {
-        key: String,
-    },
-    ReadOk {
-        value: TValue,
-    },
-    Write {
-        key: String,
-        value: TValue,
-    },
-    WriteOk,
-    Cas {
-        key: String,
-        from: TValue,
-        to: TValue,
-        create_if_not_exists: bool,
-    },
-    CasOk,
-    Error {
-        code: usize,
-        text: String,
-        in_reply_to: Option<usize>,
-    },
+#[serde(tag = "type", rename = "read")]
+struct Read<TValue> {
+    key: String,
+    #[serde(skip)]
+    _phantom: PhantomData<TValue>,
+}
+impl<TValue: Storable> Request for Read<TValue> {
+    type Response = ReadOk<TValue>;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk<TValue> {
+    value: TValue,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "write")]
+struct Write<TValue> {
+    key: String,
+    value: TValue,
+}
+impl<TValue: Storable> Request for Write<TValue> {
+    type Response = WriteOk;
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "write_ok")]
+struct WriteOk {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "cas")]
+struct Cas<TValue> {
+    key: String,
+    from: TValue,
+    to: TValue,
+    create_if_not_exists: bool,
+}
+impl<TValue: Storable> Request for Cas<TValue> {
+    type Response = CasOk;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename = "cas_ok")]
+struct CasOk {}
+
 #[derive(Clone, Debug)]
 pub struct KeyValueStore<T> {
     store_type: &'static str,
@@ -0,0 +1,116 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::watch, task::AbortHandle};
+use tracing::Instrument;
+
+/// Periodic background tasks owned by the runtime. Every timer is cancelled
+/// when the runtime shuts down, and since they sleep on tokio's clock they
+/// follow virtual time inside the simulator.
+#[derive(Clone, Debug)]
+pub struct Timers {
+    tasks: Arc<Mutex<Vec<AbortHandle>>>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new(rand::rng().random())
+    }
+}
+
+impl Timers {
+    /// The seed drives the jitter, so seeded runs fire at the same times.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            tasks: Default::default(),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Runs `task` every `interval` plus a random delay of up to `jitter`.
+    /// A tick starts only after the previous one has finished.
+    pub fn every<TTask, TFuture>(
+        &self,
+        interval: Duration,
+        jitter: Duration,
+        mut task: TTask,
+    ) -> Timer
+    where
+        TTask: FnMut() -> TFuture + Send + 'static,
+        TFuture: Future<Output = ()> + Send + 'static,
+    {
+        let (paused_tx, mut paused_rx) = watch::channel(false);
+        let rng = self.rng.clone();
+        let handle = tokio::spawn(
+            async move {
+                loop {
+                    let delay = {
+                        let mut rng = rng.lock().expect("Unable to lock timer rng");
+                        interval + rng.random_range(Duration::ZERO..=jitter)
+                    };
+                    tokio::time::sleep(delay).await;
+                    if paused_rx.wait_for(|paused| !paused).await.is_err() {
+                        return;
+                    }
+                    task().await;
+                }
+            }
+            .in_current_span(),
+        );
+
+        let abort = handle.abort_handle();
+        self.track(abort.clone());
+        Timer {
+            task: abort,
+            paused: Arc::new(paused_tx),
+        }
+    }
+
+    /// A random delay of up to `max`, from the same seeded generator as the
+    /// jitter.
+    pub(crate) fn random_delay(&self, max: Duration) -> Duration {
+        let mut rng = self.rng.lock().expect("Unable to lock timer rng");
+        rng.random_range(Duration::ZERO..=max)
+    }
+
+    /// Cancels `task` along with the timers, for background tasks that do
+    /// not run on a timer.
+    pub fn track(&self, task: AbortHandle) {
+        let mut tasks = self.tasks.lock().expect("Unable to lock timers");
+        // Short lived tasks are tracked too, so forget the finished ones.
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    pub fn cancel_all(&self) {
+        for task in self.tasks.lock().expect("Unable to lock timers").drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Handle to a single timer started with `Timers::every`.
+#[derive(Clone, Debug)]
+pub struct Timer {
+    task: AbortHandle,
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Timer {
+    /// Skips ticks until `resume` is called. A tick already running finishes.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn cancel(&self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,177 @@
+use crate::PeerStatus;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// How a node picks the peers it gossips with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopologyKind {
+    /// The neighbors from Maelstrom's `topology` message.
+    Provided,
+    /// Every other node.
+    Mesh,
+    /// The previous and the next node.
+    Ring,
+    /// Up to four neighbors, with the nodes laid out in a square grid.
+    Grid,
+    /// A spanning tree in which every node has up to `arity` children.
+    Tree { arity: usize },
+}
+
+impl TopologyKind {
+    /// Reads `GOSSIP_TOPOLOGY`: `provided` (the default), `mesh`, `ring`,
+    /// `grid`, or `tree` with an optional arity such as `tree:4`.
+    pub fn from_env() -> Self {
+        let value = std::env::var("GOSSIP_TOPOLOGY").unwrap_or_default();
+        match value.split_once(':').unwrap_or((&value, "")) {
+            ("mesh", _) => Self::Mesh,
+            ("ring", _) => Self::Ring,
+            ("grid", _) => Self::Grid,
+            ("tree", arity) => Self::Tree {
+                arity: arity.parse().unwrap_or(4).max(1),
+            },
+            _ => Self::Provided,
+        }
+    }
+}
+
+/// The peers a node gossips with.
+///
+/// Callers report the acks they get from peers and the ones they miss. Once a
+/// neighbor has missed `max_missed_acks` acks in a row its link looks
+/// partitioned, and `peers` falls back to the full mesh, routing around the
+/// link until the neighbor acks again.
+#[derive(Clone, Debug)]
+pub struct Topology {
+    node_id: String,
+    all_nodes: Vec<String>,
+    kind: TopologyKind,
+    neighbors: Arc<Mutex<Vec<String>>>,
+    missed_acks: Arc<Mutex<HashMap<String, usize>>>,
+    max_missed_acks: usize,
+}
+
+impl Topology {
+    pub fn new(node_id: String, all_nodes: Vec<String>, kind: TopologyKind) -> Self {
+        let neighbors = compute(&node_id, &all_nodes, &kind);
+        Self {
+            node_id,
+            all_nodes,
+            kind,
+            neighbors: Arc::new(Mutex::new(neighbors)),
+            missed_acks: Default::default(),
+            max_missed_acks: 3,
+        }
+    }
+
+    /// Adopts the neighbors Maelstrom assigned to this node. Ignored unless
+    /// the topology is `Provided`.
+    pub fn set_provided(&self, topology: &HashMap<String, Vec<String>>) {
+        if self.kind != TopologyKind::Provided {
+            return;
+        }
+        if let Some(neighbors) = topology.get(&self.node_id) {
+            *self.neighbors.lock().expect("Unable to lock neighbors") = neighbors.clone();
+        }
+    }
+
+    pub fn neighbors(&self) -> Vec<String> {
+        self.neighbors
+            .lock()
+            .expect("Unable to lock neighbors")
+            .clone()
+    }
+
+    /// The peers to gossip with: the neighbors, or every other node while
+    /// the link to a neighbor looks partitioned.
+    pub fn peers(&self) -> Vec<String> {
+        let neighbors = self.neighbors();
+        if neighbors.iter().any(|neighbor| self.is_suspected(neighbor)) {
+            return compute(&self.node_id, &self.all_nodes, &TopologyKind::Mesh);
+        }
+        neighbors
+    }
+
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        let missed_acks = self.missed_acks.lock().expect("Unable to lock acks");
+        missed_acks.get(peer).copied().unwrap_or_default() >= self.max_missed_acks
+    }
+
+    /// For fire-and-forget gossip: a send counts as a missed ack until the
+    /// peer acks it.
+    pub fn record_sent(&self, peer: &str) {
+        self.record_missed(peer);
+    }
+
+    /// For calls to `peer` that failed or timed out.
+    pub fn record_missed(&self, peer: &str) {
+        let mut missed_acks = self.missed_acks.lock().expect("Unable to lock acks");
+        *missed_acks.entry(peer.to_string()).or_default() += 1;
+    }
+
+    pub fn record_ack(&self, peer: &str) {
+        let mut missed_acks = self.missed_acks.lock().expect("Unable to lock acks");
+        missed_acks.remove(peer);
+    }
+
+    /// For a failure detector's verdict on `peer`, which overrides the acks
+    /// counted so far.
+    pub fn record_status(&self, peer: &str, status: PeerStatus) {
+        match status {
+            PeerStatus::Suspected => {
+                let mut missed_acks = self.missed_acks.lock().expect("Unable to lock acks");
+                missed_acks.insert(peer.to_string(), self.max_missed_acks);
+            }
+            PeerStatus::Alive => self.record_ack(peer),
+            PeerStatus::Unknown => {}
+        }
+    }
+}
+
+/// The neighbors of `node_id` in `kind`, for the computed topologies. The
+/// `Provided` topology starts out as the full mesh until Maelstrom sends it.
+fn compute(node_id: &str, all_nodes: &[String], kind: &TopologyKind) -> Vec<String> {
+    let Some(index) = all_nodes.iter().position(|node| node == node_id) else {
+        return Vec::new();
+    };
+    let n = all_nodes.len();
+    let indexes: Vec<usize> = match kind {
+        TopologyKind::Provided | TopologyKind::Mesh => (0..n).collect(),
+        TopologyKind::Ring => vec![(index + n - 1) % n, (index + 1) % n],
+        TopologyKind::Grid => {
+            let width = (n as f64).sqrt().ceil() as usize;
+            let mut indexes = vec![];
+            if index % width > 0 {
+                indexes.push(index - 1);
+            }
+            if index % width + 1 < width && index + 1 < n {
+                indexes.push(index + 1);
+            }
+            if index >= width {
+                indexes.push(index - width);
+            }
+            if index + width < n {
+                indexes.push(index + width);
+            }
+            indexes
+        }
+        TopologyKind::Tree { arity } => {
+            let mut indexes: Vec<usize> = (index * arity + 1..=index * arity + arity)
+                .filter(|child| *child < n)
+                .collect();
+            if index > 0 {
+                indexes.push((index - 1) / arity);
+            }
+            indexes
+        }
+    };
+
+    let mut neighbors: Vec<String> = indexes
+        .into_iter()
+        .filter(|i| *i != index)
+        .map(|i| all_nodes[i].clone())
+        .collect();
+    neighbors.dedup();
+    neighbors
+}
@@ -0,0 +1,155 @@
+#[cfg(unix)]
+use std::path::Path;
+use std::{future::Future, io};
+#[cfg(unix)]
+use tokio::net::{unix, UnixStream};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, Lines, Stdin,
+        Stdout,
+    },
+    net::{tcp, TcpStream, ToSocketAddrs},
+    sync::mpsc::{Receiver, Sender},
+};
+
+/// A bidirectional, line oriented connection between a node and the rest of
+/// the cluster. Every line carries one JSON encoded message.
+pub trait Transport: Send + 'static {
+    type Reader: TransportReader;
+    type Writer: TransportWriter;
+
+    fn split(self) -> (Self::Reader, Self::Writer);
+}
+
+pub trait TransportReader: Send + 'static {
+    /// Returns the next line, or `None` once the connection is closed.
+    fn recv(&mut self) -> impl Future<Output = io::Result<Option<String>>> + Send;
+}
+
+pub trait TransportWriter: Send + 'static {
+    fn send(&mut self, line: String) -> impl Future<Output = io::Result<()>> + Send;
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Newline delimited messages over any async byte stream.
+pub struct StreamTransport<R, W> {
+    reader: R,
+    writer: W,
+}
+
+pub type StdioTransport = StreamTransport<Stdin, Stdout>;
+pub type TcpTransport = StreamTransport<tcp::OwnedReadHalf, tcp::OwnedWriteHalf>;
+#[cfg(unix)]
+pub type UnixTransport = StreamTransport<unix::OwnedReadHalf, unix::OwnedWriteHalf>;
+
+impl<R, W> StreamTransport<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl StdioTransport {
+    /// The transport Maelstrom uses: messages in on stdin, out on stdout.
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl TcpTransport {
+    pub fn from_stream(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self::new(reader, writer)
+    }
+
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::from_stream(TcpStream::connect(addr).await?))
+    }
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn from_stream(stream: UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self::new(reader, writer)
+    }
+
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_stream(UnixStream::connect(path).await?))
+    }
+}
+
+impl<R, W> Transport for StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    type Reader = LineReader<R>;
+    type Writer = LineWriter<W>;
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        (
+            LineReader(BufReader::new(self.reader).lines()),
+            LineWriter(BufWriter::new(self.writer)),
+        )
+    }
+}
+
+pub struct LineReader<R>(Lines<BufReader<R>>);
+
+impl<R: AsyncRead + Unpin + Send + 'static> TransportReader for LineReader<R> {
+    async fn recv(&mut self) -> io::Result<Option<String>> {
+        self.0.next_line().await
+    }
+}
+
+pub struct LineWriter<W>(BufWriter<W>);
+
+impl<W: AsyncWrite + Unpin + Send + 'static> TransportWriter for LineWriter<W> {
+    async fn send(&mut self, line: String) -> io::Result<()> {
+        self.0.write_all(line.as_bytes()).await?;
+        self.0.write_all(b"\n").await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.0.flush().await
+    }
+}
+
+/// Messages over in-memory channels, for running nodes inside one process.
+pub struct ChannelTransport {
+    inbound: Receiver<String>,
+    outbound: Sender<String>,
+}
+
+impl ChannelTransport {
+    pub fn new(inbound: Receiver<String>, outbound: Sender<String>) -> Self {
+        Self { inbound, outbound }
+    }
+}
+
+impl Transport for ChannelTransport {
+    type Reader = Receiver<String>;
+    type Writer = Sender<String>;
+
+    fn split(self) -> (Self::Reader, Self::Writer) {
+        (self.inbound, self.outbound)
+    }
+}
+
+impl TransportReader for Receiver<String> {
+    async fn recv(&mut self) -> io::Result<Option<String>> {
+        Ok(Receiver::recv(self).await)
+    }
+}
+
+impl TransportWriter for Sender<String> {
+    async fn send(&mut self, line: String) -> io::Result<()> {
+        Sender::send(self, line)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+#[allow(dead_code)]
+#[path = "../src/bin/broadcast.rs"]
+mod broadcast;
+#[allow(dead_code)]
+#[path = "../src/bin/broadcast-rpc.rs"]
+mod broadcast_rpc;
+
+use gossip::{block_on, Node, Simulator, SimulatorConfig};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Broadcasts on both sides of a partition, heals it, and checks that every
+/// node ends up reading every value.
+async fn converges_after_a_partition<TNode: Node<Value> + Clone + 'static>() {
+    let simulator = Simulator::<Value, TNode>::start(SimulatorConfig::default())
+        .await
+        .unwrap();
+    simulator.partition(&[&["n0"], &["n1", "n2"]]);
+    for (node_id, message) in [("n0", 1), ("n1", 2), ("n2", 3)] {
+        let reply = simulator
+            .rpc(node_id, json!({"type": "broadcast", "message": message}))
+            .await
+            .unwrap();
+        assert_eq!(reply.body.payload["type"], "broadcast_ok");
+    }
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let read = simulator.rpc("n0", json!({"type": "read"})).await.unwrap();
+    assert_eq!(read.body.payload["messages"], json!([1]));
+
+    simulator.heal();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    for node_id in simulator.node_ids() {
+        let read = simulator
+            .rpc(node_id, json!({"type": "read"}))
+            .await
+            .unwrap();
+        let mut messages: Vec<u64> =
+            serde_json::from_value(read.body.payload["messages"].clone()).unwrap();
+        messages.sort();
+        assert_eq!(messages, vec![1, 2, 3], "{node_id} did not converge");
+    }
+}
+
+#[test]
+fn broadcast_converges_after_a_partition() {
+    block_on(converges_after_a_partition::<broadcast::BoradcastNode>());
+}
+
+#[test]
+fn broadcast_rpc_converges_after_a_partition() {
+    block_on(converges_after_a_partition::<broadcast_rpc::BoradcastNode>());
+}
+
+/// Broadcasts to a cluster whose round trips outlast the gossip interval and
+/// checks that gossip stops once every node has every value.
+async fn goes_quiet_after_converging<TNode: Node<Value> + Clone + 'static>() {
+    let config = SimulatorConfig {
+        node_count: 5,
+        min_latency: Duration::from_millis(100),
+        max_latency: Duration::from_millis(100),
+        ..SimulatorConfig::default()
+    };
+    let simulator = Simulator::<Value, TNode>::start(config).await.unwrap();
+    for (i, node_id) in simulator.node_ids().iter().enumerate() {
+        simulator
+            .rpc(node_id, json!({"type": "broadcast", "message": i}))
+            .await
+            .unwrap();
+    }
+
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    for node_id in simulator.node_ids() {
+        let read = simulator
+            .rpc(node_id, json!({"type": "read"}))
+            .await
+            .unwrap();
+        assert_eq!(read.body.payload["messages"], json!([0, 1, 2, 3, 4]));
+    }
+    let gossiped = simulator.sent("gossip");
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(
+        simulator.sent("gossip"),
+        gossiped,
+        "gossip never went quiet"
+    );
+}
+
+#[test]
+fn broadcast_goes_quiet_after_converging() {
+    block_on(goes_quiet_after_converging::<broadcast::BoradcastNode>());
+}
+
+#[test]
+fn broadcast_rpc_goes_quiet_after_converging() {
+    block_on(goes_quiet_after_converging::<broadcast_rpc::BoradcastNode>());
+}
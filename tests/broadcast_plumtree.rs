@@ -0,0 +1,59 @@
+//! Runs in its own test binary because `GOSSIP_MODE` is read from the
+//! process environment when a node initializes.
+
+#[allow(dead_code)]
+#[path = "../src/bin/broadcast-rpc.rs"]
+mod broadcast_rpc;
+
+use gossip::{block_on, Simulator, SimulatorConfig};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Broadcasts on both sides of a partition, heals it, and checks that every
+/// node ends up reading every value even though the eager tree was pruned.
+#[test]
+fn plumtree_converges_after_a_partition() {
+    std::env::set_var("GOSSIP_MODE", "plumtree");
+    block_on(async {
+        let config = SimulatorConfig {
+            node_count: 5,
+            ..SimulatorConfig::default()
+        };
+        let simulator = Simulator::<Value, broadcast_rpc::BoradcastNode>::start(config)
+            .await
+            .unwrap();
+        for (i, node_id) in simulator.node_ids().iter().enumerate() {
+            simulator
+                .rpc(node_id, json!({"type": "broadcast", "message": i}))
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(simulator.sent("prune") > 0, "plumtree is not running");
+
+        simulator.partition(&[&["n0", "n1"], &["n2", "n3", "n4"]]);
+        for (node_id, message) in [("n0", 10), ("n3", 11)] {
+            simulator
+                .rpc(node_id, json!({"type": "broadcast", "message": message}))
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let read = simulator.rpc("n1", json!({"type": "read"})).await.unwrap();
+        assert_eq!(read.body.payload["messages"], json!([0, 1, 2, 3, 4, 10]));
+
+        simulator.heal();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        for node_id in simulator.node_ids() {
+            let read = simulator
+                .rpc(node_id, json!({"type": "read"}))
+                .await
+                .unwrap();
+            assert_eq!(
+                read.body.payload["messages"],
+                json!([0, 1, 2, 3, 4, 10, 11]),
+                "{node_id} did not converge"
+            );
+        }
+    });
+}
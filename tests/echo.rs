@@ -0,0 +1,25 @@
+#[allow(dead_code)]
+#[path = "../src/bin/echo.rs"]
+mod echo;
+
+use echo::EchoNode;
+use gossip::{block_on, Simulator, SimulatorConfig};
+use serde_json::{json, Value};
+
+#[test]
+fn echo_round_trips() {
+    block_on(async {
+        let simulator = Simulator::<Value, EchoNode>::start(SimulatorConfig::default())
+            .await
+            .unwrap();
+        for node_id in simulator.node_ids() {
+            let reply = simulator
+                .rpc(node_id, json!({"type": "echo", "echo": node_id}))
+                .await
+                .unwrap();
+            assert_eq!(reply.src, *node_id);
+            assert_eq!(reply.body.payload["type"], "echo_ok");
+            assert_eq!(reply.body.payload["echo"], *node_id);
+        }
+    });
+}
@@ -0,0 +1,133 @@
+use gossip::{
+    block_on, ElectionConfig, LeaderElection, Message, Network, Node, Simulator, SimulatorConfig,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Campaigns for one lease and answers `leader` requests with what it sees.
+#[derive(Clone)]
+struct ElectionNode {
+    election: LeaderElection,
+    network: Network,
+    /// Every leader `subscribe` reported, in order.
+    changes: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl Node<Value> for ElectionNode {
+    fn from_init(_id: String, _node_ids: Vec<String>, network: Network) -> Self {
+        let election = LeaderElection::start(&network, "leader", ElectionConfig::default());
+        let changes: Arc<Mutex<Vec<Option<String>>>> = Default::default();
+        let mut leader = election.subscribe();
+        let recorded = changes.clone();
+        let task = tokio::spawn(async move {
+            while leader.changed().await.is_ok() {
+                let current = leader.borrow_and_update().clone();
+                recorded.lock().unwrap().push(current);
+            }
+        });
+        network.timers().track(task.abort_handle());
+        Self {
+            election,
+            network,
+            changes,
+        }
+    }
+
+    async fn handle_message(&self, msg: Message<Value>) -> anyhow::Result<()> {
+        let reply = msg.reply(json!({
+            "type": "leader_ok",
+            "leader": self.election.current_leader(),
+            "is_leader": self.election.is_leader(),
+            "term": self.election.term(),
+            "changes": *self.changes.lock().unwrap(),
+        }));
+        self.network.send(&reply).await;
+        Ok(())
+    }
+}
+
+type Cluster = Simulator<Value, ElectionNode>;
+
+async fn ask(simulator: &Cluster, node_id: &str) -> Value {
+    simulator
+        .rpc(node_id, json!({"type": "leader"}))
+        .await
+        .unwrap()
+        .body
+        .payload
+}
+
+/// The nodes that think they lead, with their terms, checking that there is
+/// at most one.
+async fn leaders(simulator: &Cluster) -> Vec<(String, u64)> {
+    let mut leaders = Vec::new();
+    for node_id in simulator.node_ids() {
+        let reply = ask(simulator, node_id).await;
+        if reply["is_leader"] == true {
+            leaders.push((node_id.clone(), reply["term"].as_u64().unwrap()));
+        }
+    }
+    assert!(leaders.len() <= 1, "several leaders at once: {leaders:?}");
+    leaders
+}
+
+#[test]
+fn one_leader_per_term_across_a_partition() {
+    block_on(async {
+        let simulator = Cluster::start(SimulatorConfig::default()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let Ok([(leader, first_term)]) = <[_; 1]>::try_from(leaders(&simulator).await) else {
+            panic!("no leader was elected");
+        };
+        for node_id in simulator.node_ids() {
+            assert_eq!(ask(&simulator, node_id).await["leader"], leader);
+        }
+
+        // Cut the leader off from everyone, lin-kv included.
+        let others: Vec<&str> = simulator
+            .node_ids()
+            .iter()
+            .map(String::as_str)
+            .filter(|node_id| *node_id != leader)
+            .chain(["lin-kv"])
+            .collect();
+        simulator.partition(&[&[leader.as_str()], &others]);
+
+        let mut leaders_by_term: HashMap<u64, HashSet<String>> = HashMap::new();
+        for _ in 0..40 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            for (node_id, term) in leaders(&simulator).await {
+                leaders_by_term.entry(term).or_default().insert(node_id);
+            }
+        }
+        for (term, leaders) in &leaders_by_term {
+            assert_eq!(leaders.len(), 1, "term {term} had leaders {leaders:?}");
+        }
+        let Ok([(new_leader, new_term)]) = <[_; 1]>::try_from(leaders(&simulator).await) else {
+            panic!("nobody took over");
+        };
+        assert_ne!(new_leader, leader);
+        assert!(new_term > first_term);
+
+        // The remaining nodes were told about the failover.
+        for node_id in others.iter().filter(|node_id| **node_id != "lin-kv") {
+            let changes = ask(&simulator, node_id).await["changes"].clone();
+            let changes: Vec<Option<String>> = serde_json::from_value(changes).unwrap();
+            let old = changes
+                .iter()
+                .position(|change| *change == Some(leader.clone()));
+            let new = changes
+                .iter()
+                .rposition(|change| *change == Some(new_leader.clone()));
+            assert!(
+                old.is_some() && old < new,
+                "{node_id} saw {changes:?}, not {leader} then {new_leader}"
+            );
+        }
+        assert_eq!(ask(&simulator, &leader).await["is_leader"], false);
+    });
+}
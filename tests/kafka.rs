@@ -0,0 +1,147 @@
+#[allow(dead_code)]
+#[path = "../src/bin/kafka.rs"]
+mod kafka;
+
+use gossip::{block_on, RuntimeConfig, Simulator, SimulatorConfig};
+use kafka::KafkaNode;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinSet;
+
+#[test]
+fn sends_get_increasing_offsets_and_commits_are_shared() {
+    block_on(async {
+        let config = SimulatorConfig {
+            runtime: RuntimeConfig {
+                dedup_window: 4096,
+                ..RuntimeConfig::default()
+            },
+            ..SimulatorConfig::default()
+        };
+        let simulator = Simulator::<Value, KafkaNode>::start(config).await.unwrap();
+
+        let mut offsets = Vec::new();
+        for (node_id, msg) in [("n0", 5), ("n1", 6)] {
+            let reply = simulator
+                .rpc(node_id, json!({"type": "send", "key": "k1", "msg": msg}))
+                .await
+                .unwrap();
+            assert_eq!(reply.body.payload["type"], "send_ok");
+            offsets.push(reply.body.payload["offset"].as_u64().unwrap());
+        }
+        assert!(offsets[0] < offsets[1]);
+
+        // Polls are served from the node's own copy of the log, which the
+        // last append brought up to date.
+        let reply = simulator
+            .rpc("n1", json!({"type": "poll", "offsets": {"k1": offsets[0]}}))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply.body.payload["msgs"]["k1"],
+            json!([[offsets[0], 5], [offsets[1], 6]])
+        );
+
+        let reply = simulator
+            .rpc(
+                "n0",
+                json!({"type": "commit_offsets", "offsets": {"k1": offsets[1]}}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.body.payload["type"], "commit_offsets_ok");
+        let reply = simulator
+            .rpc(
+                "n1",
+                json!({"type": "list_committed_offsets", "keys": ["k1"]}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.body.payload["offsets"]["k1"], offsets[1]);
+    });
+}
+
+#[test]
+fn saturated_nodes_still_get_their_kv_replies() {
+    block_on(async {
+        let config = SimulatorConfig {
+            runtime: RuntimeConfig {
+                max_in_flight: 2,
+                inbound_queue: 2,
+                dedup_window: 4096,
+                ..RuntimeConfig::default()
+            },
+            ..SimulatorConfig::default()
+        };
+        let simulator = Arc::new(Simulator::<Value, KafkaNode>::start(config).await.unwrap());
+
+        // Every send makes kv rpcs, whose replies queue behind the sends.
+        let mut sends = JoinSet::new();
+        for msg in 0..12 {
+            let simulator = simulator.clone();
+            sends.spawn(async move {
+                simulator
+                    .rpc("n0", json!({"type": "send", "key": "k1", "msg": msg}))
+                    .await
+            });
+        }
+        let mut offsets = Vec::new();
+        while let Some(reply) = sends.join_next().await {
+            let reply = reply.unwrap().unwrap();
+            assert_eq!(reply.body.payload["type"], "send_ok");
+            offsets.push(reply.body.payload["offset"].as_u64().unwrap());
+        }
+        offsets.sort();
+        assert_eq!(offsets, (0..12).collect::<Vec<_>>());
+    });
+}
+
+#[test]
+fn appends_whose_kv_replies_time_out_land_once() {
+    block_on(async {
+        // The simulated kv service answers right away, so its round trips
+        // take one latency, which straddles the one second rpc timeout.
+        let config = SimulatorConfig {
+            min_latency: Duration::from_millis(800),
+            max_latency: Duration::from_millis(1200),
+            rpc_timeout: Duration::from_secs(60),
+            runtime: RuntimeConfig {
+                dedup_window: 4096,
+                ..RuntimeConfig::default()
+            },
+            ..SimulatorConfig::default()
+        };
+        let simulator = Simulator::<Value, KafkaNode>::start(config).await.unwrap();
+
+        let mut sent = Vec::new();
+        for msg in 0..10 {
+            let reply = simulator
+                .rpc("n0", json!({"type": "send", "key": "k1", "msg": msg}))
+                .await
+                .unwrap();
+            if reply.body.payload["type"] == "send_ok" {
+                sent.push((reply.body.payload["offset"].as_u64().unwrap(), msg));
+            }
+        }
+        assert!(!sent.is_empty());
+
+        let reply = simulator
+            .rpc("n0", json!({"type": "poll", "offsets": {"k1": 0}}))
+            .await
+            .unwrap();
+        let log: Vec<(u64, u64)> =
+            serde_json::from_value(reply.body.payload["msgs"]["k1"].clone()).unwrap();
+        // A send that failed with a timeout may still have landed, but none
+        // lands twice.
+        let mut msgs: Vec<u64> = log.iter().map(|(_, msg)| *msg).collect();
+        msgs.dedup();
+        assert_eq!(
+            msgs.len(),
+            log.len(),
+            "a message was appended twice: {log:?}"
+        );
+        for entry in sent {
+            assert!(log.contains(&entry), "{entry:?} is not in {log:?}");
+        }
+    });
+}
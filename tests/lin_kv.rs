@@ -0,0 +1,120 @@
+#[allow(dead_code)]
+#[path = "../src/bin/lin-kv.rs"]
+mod lin_kv;
+
+use gossip::{block_on, Simulator, SimulatorConfig};
+use lin_kv::LinKvNode;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinSet;
+
+/// Entries applied before Raft compacts its log, by default.
+const SNAPSHOT_THRESHOLD: u64 = 1024;
+
+type Cluster = Simulator<Value, LinKvNode>;
+
+async fn start() -> Cluster {
+    Simulator::start(SimulatorConfig::default()).await.unwrap()
+}
+
+async fn request(simulator: &Cluster, node_id: &str, payload: Value) -> Value {
+    simulator.rpc(node_id, payload).await.unwrap().body.payload
+}
+
+/// Retries `payload` on `node_id` until it succeeds, as a client would while
+/// a leader is elected.
+async fn until_ok(simulator: &Cluster, node_id: &str, payload: Value) -> Value {
+    for _ in 0..20 {
+        let reply = request(simulator, node_id, payload.clone()).await;
+        if reply["type"] != "error" {
+            return reply;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{payload} never succeeded on {node_id}");
+}
+
+fn write(key: u64, value: u64) -> Value {
+    json!({"type": "write", "key": key, "value": value})
+}
+
+fn read(key: u64) -> Value {
+    json!({"type": "read", "key": key})
+}
+
+#[test]
+fn only_the_majority_side_of_a_partition_accepts_writes() {
+    block_on(async {
+        let simulator = start().await;
+        until_ok(&simulator, "n0", write(1, 1)).await;
+
+        simulator.partition(&[&["n0"], &["n1", "n2"]]);
+        let reply = request(&simulator, "n0", write(1, 2)).await;
+        assert_eq!(reply["type"], "error", "the minority accepted a write");
+        let reply = until_ok(&simulator, "n1", write(1, 3)).await;
+        assert_eq!(reply["type"], "write_ok");
+
+        // Reads agree with the majority's write everywhere once healed, and
+        // the minority's write never shows.
+        simulator.heal();
+        for node_id in simulator.node_ids() {
+            let reply = until_ok(&simulator, node_id, read(1)).await;
+            assert_eq!(reply["value"], 3, "{node_id} read a stale value");
+        }
+    });
+}
+
+#[test]
+fn cas_fails_on_a_mismatch_and_missing_keys() {
+    block_on(async {
+        let simulator = start().await;
+        until_ok(&simulator, "n0", write(1, 1)).await;
+
+        let cas = |from, to| json!({"type": "cas", "key": 1, "from": from, "to": to});
+        let reply = until_ok(&simulator, "n1", cas(1, 2)).await;
+        assert_eq!(reply["type"], "cas_ok");
+        let reply = request(&simulator, "n2", cas(1, 3)).await;
+        assert_eq!(reply["code"], 22);
+        let reply = request(&simulator, "n2", read(7)).await;
+        assert_eq!(reply["code"], 20);
+    });
+}
+
+#[test]
+fn lagging_nodes_catch_up_through_a_snapshot() {
+    block_on(async {
+        let simulator = Arc::new(start().await);
+        until_ok(&simulator, "n0", write(0, 0)).await;
+
+        // Enough writes while n2 is away that the log it misses is compacted.
+        simulator.partition(&[&["n0", "n1"], &["n2"]]);
+        until_ok(&simulator, "n0", write(0, 0)).await;
+        let writes = SNAPSHOT_THRESHOLD + 100;
+        for batch in (0..writes).collect::<Vec<_>>().chunks(64) {
+            let mut requests = JoinSet::new();
+            for value in batch.iter().copied() {
+                let simulator = simulator.clone();
+                requests.spawn(async move {
+                    until_ok(&simulator, "n0", write(value % 8, value)).await;
+                });
+            }
+            while let Some(result) = requests.join_next().await {
+                result.unwrap();
+            }
+        }
+        // The batches land in any order, so end on known values.
+        for key in 0..8 {
+            until_ok(&simulator, "n0", write(key, writes + key)).await;
+        }
+        assert_eq!(simulator.sent("raft_snapshot_ok"), 0);
+
+        simulator.heal();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        // Only a node that installed a snapshot acks one.
+        assert!(simulator.sent("raft_snapshot_ok") > 0, "n2 got no snapshot");
+        for key in 0..8 {
+            let reply = until_ok(&simulator, "n2", read(key)).await;
+            assert_eq!(reply["value"], writes + key);
+        }
+    });
+}
@@ -0,0 +1,70 @@
+#[allow(dead_code)]
+#[path = "../src/bin/txn.rs"]
+mod txn;
+
+use gossip::{block_on, RuntimeConfig, Simulator, SimulatorConfig};
+use serde_json::{json, Value};
+use std::time::Duration;
+use txn::TxnNode;
+
+#[test]
+fn txns_replicate_once_a_partition_heals() {
+    block_on(async {
+        let config = SimulatorConfig {
+            runtime: RuntimeConfig {
+                dedup_window: 4096,
+                ..RuntimeConfig::default()
+            },
+            ..SimulatorConfig::default()
+        };
+        let simulator = Simulator::<Value, TxnNode>::start(config).await.unwrap();
+        let read = |key: usize| json!({"type": "txn", "txn": [["r", key, null]]});
+
+        simulator.partition(&[&["n0"], &["n1", "n2"]]);
+        let reply = simulator
+            .rpc("n0", json!({"type": "txn", "txn": [["w", 1, 10]]}))
+            .await
+            .unwrap();
+        assert_eq!(reply.body.payload["type"], "txn_ok");
+        assert_eq!(reply.body.payload["txn"], json!([["w", 1, 10]]));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let reply = simulator.rpc("n1", read(1)).await.unwrap();
+        assert_eq!(reply.body.payload["txn"], json!([["r", 1, null]]));
+
+        simulator.heal();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        for node_id in simulator.node_ids() {
+            let reply = simulator.rpc(node_id, read(1)).await.unwrap();
+            assert_eq!(
+                reply.body.payload["txn"],
+                json!([["r", 1, 10]]),
+                "{node_id} missed the write"
+            );
+        }
+    });
+}
+
+#[test]
+fn malformed_txns_get_an_error_reply() {
+    block_on(async {
+        let simulator = Simulator::<Value, TxnNode>::start(SimulatorConfig::default())
+            .await
+            .unwrap();
+        for txn in [json!([["w", 1, null]]), json!([["x", 1, 2]])] {
+            let reply = simulator
+                .rpc("n0", json!({"type": "txn", "txn": txn}))
+                .await
+                .unwrap();
+            assert_eq!(reply.body.payload["type"], "error");
+            assert_eq!(reply.body.payload["code"], 12, "{txn} is malformed");
+        }
+
+        // The node is still up.
+        let reply = simulator
+            .rpc("n0", json!({"type": "txn", "txn": [["w", 1, 2]]}))
+            .await
+            .unwrap();
+        assert_eq!(reply.body.payload["type"], "txn_ok");
+    });
+}